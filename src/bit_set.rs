@@ -0,0 +1,354 @@
+use std::ops::DerefMut;
+
+use crate::virtual_vec::VirtualVec;
+
+/// Storage for the words of a `BitSet`.
+pub trait Words: DerefMut<Target = [u64]> {
+    /// Grows to at least `min_len` words, with the new words zeroed.
+    fn grow_zeroed(&mut self, min_len: usize);
+}
+
+impl Words for Vec<u64> {
+    fn grow_zeroed(&mut self, min_len: usize) {
+        // Double to keep growth amortized.
+        let len = usize::max(min_len, self.len() * 2);
+        self.resize(len, 0);
+    }
+}
+
+impl Words for VirtualVec<u64> {
+    fn grow_zeroed(&mut self, min_len: usize) {
+        let additional = min_len.saturating_sub(self.len());
+        self.reserve(additional);
+        for _ in 0..additional {
+            self.push(0);
+        }
+    }
+}
+
+/// A growable set of indices stored as a bitmap, with a summary bitmap recording which words are
+/// non-empty. Iterating or intersecting a set only visits the summary and the non-empty words, so
+/// a few hundred indices spread over a million cost a few hundred word reads rather than 16K.
+#[derive(Clone, Default)]
+pub struct BitSet<W = Vec<u64>> {
+    words: W,
+    /// Bit `i` is set if `words[i]` is non-zero.
+    summary: W,
+}
+
+impl BitSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl BitSet<VirtualVec<u64>> {
+    /// Creates a set for indices below `bits`, whose words are reserved up front so growing only
+    /// commits pages and never moves them.
+    pub fn with_mapping(bits: usize) -> Self {
+        let words = usize::max(bits.div_ceil(64), 1);
+        Self {
+            words: VirtualVec::new(words),
+            summary: VirtualVec::new(words.div_ceil(64)),
+        }
+    }
+}
+
+impl<W: Words> BitSet<W> {
+    /// Adds `index` to the set, returning false if it was already present.
+    pub fn set(&mut self, index: usize) -> bool {
+        let word = index / 64;
+        if word >= self.words.len() {
+            self.grow(word + 1);
+        }
+
+        let previous = self.words[word];
+        let mask = 1 << (index % 64);
+        self.words[word] = previous | mask;
+        if previous == 0 {
+            self.summary[word / 64] |= 1 << (word % 64);
+        }
+        previous & mask == 0
+    }
+
+    /// Removes `index` from the set, returning false if it wasn't present.
+    pub fn unset(&mut self, index: usize) -> bool {
+        let word = index / 64;
+        let bits = match self.words.get_mut(word) {
+            Some(bits) => bits,
+            None => return false,
+        };
+
+        let mask = 1 << (index % 64);
+        if *bits & mask == 0 {
+            return false;
+        }
+        *bits &= !mask;
+        if *bits == 0 {
+            self.summary[word / 64] &= !(1 << (word % 64));
+        }
+        true
+    }
+
+    #[inline]
+    pub fn contains(&self, index: usize) -> bool {
+        self.as_slice().contains(index)
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.summary.iter().all(|&word| word == 0)
+    }
+
+    /// Returns the lowest index not in the set.
+    pub fn first_unset(&self) -> usize {
+        self.words
+            .iter()
+            .enumerate()
+            .find(|(_, &word)| word != u64::MAX)
+            .map_or(self.words.len() * 64, |(index, &word)| {
+                index * 64 + (!word).trailing_zeros() as usize
+            })
+    }
+
+    /// Iterates over the indices in the set in ascending order.
+    #[inline]
+    pub fn iter(&self) -> Iter<'_> {
+        self.as_slice().iter()
+    }
+
+    /// Returns the indices present in both `self` and `other`.
+    pub fn and<V: Words>(&self, other: &BitSet<V>) -> BitSet {
+        self.as_slice().and(other.as_slice())
+    }
+
+    #[inline]
+    pub fn as_slice(&self) -> BitSlice<'_> {
+        BitSlice {
+            words: &self.words,
+            summary: &self.summary,
+        }
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn grow(&mut self, words: usize) {
+        self.words.grow_zeroed(words);
+        self.summary.grow_zeroed(self.words.len().div_ceil(64));
+    }
+}
+
+/// A borrowed view of a `BitSet`, whatever its storage.
+#[derive(Copy, Clone)]
+pub struct BitSlice<'a> {
+    words: &'a [u64],
+    summary: &'a [u64],
+}
+
+impl<'a> BitSlice<'a> {
+    #[inline]
+    pub fn contains(self, index: usize) -> bool {
+        self.words
+            .get(index / 64)
+            .is_some_and(|&word| word & (1 << (index % 64)) != 0)
+    }
+
+    /// The bitmap words, with bit `i % 64` of word `i / 64` set for each index `i` in the set.
+    #[inline]
+    pub fn words(self) -> &'a [u64] {
+        self.words
+    }
+
+    #[inline]
+    pub fn iter(self) -> Iter<'a> {
+        Iter {
+            words: self.words,
+            nonempty: Bits::new(self.summary),
+            base: 0,
+            word: 0,
+        }
+    }
+
+    /// Returns the indices present in both `self` and `other`, only visiting words which are
+    /// non-empty in both.
+    pub fn and(self, other: BitSlice<'_>) -> BitSet {
+        let len = usize::min(self.words.len(), other.words.len());
+        let mut result = BitSet {
+            words: vec![0; len],
+            summary: vec![0; len.div_ceil(64)],
+        };
+
+        for (index, summary) in result.summary.iter_mut().enumerate() {
+            let mut nonempty = self.summary[index] & other.summary[index];
+            while nonempty != 0 {
+                let word = index * 64 + nonempty.trailing_zeros() as usize;
+                nonempty &= nonempty - 1;
+                let bits = self.words[word] & other.words[word];
+                if bits != 0 {
+                    result.words[word] = bits;
+                    *summary |= 1 << (word % 64);
+                }
+            }
+        }
+        result
+    }
+
+    /// Copies the view into a set of its own.
+    pub fn to_bit_set(self) -> BitSet {
+        BitSet {
+            words: self.words.to_vec(),
+            summary: self.summary.to_vec(),
+        }
+    }
+}
+
+/// Iterator over the set bits of a slice of words, skipping clear bits with `trailing_zeros`.
+struct Bits<'a> {
+    words: std::slice::Iter<'a, u64>,
+    /// Number of words loaded so far, including `word`.
+    loaded: usize,
+    /// Bits of the current word which haven't been yielded yet.
+    word: u64,
+}
+
+impl<'a> Bits<'a> {
+    #[inline]
+    fn new(words: &'a [u64]) -> Self {
+        Self {
+            words: words.iter(),
+            loaded: 0,
+            word: 0,
+        }
+    }
+}
+
+impl Iterator for Bits<'_> {
+    type Item = usize;
+
+    #[inline]
+    fn next(&mut self) -> Option<usize> {
+        while self.word == 0 {
+            self.word = *self.words.next()?;
+            self.loaded += 1;
+        }
+        let bit = self.word.trailing_zeros() as usize;
+        // Clear the lowest set bit.
+        self.word &= self.word - 1;
+        Some((self.loaded - 1) * 64 + bit)
+    }
+}
+
+/// Iterator over the indices of a `BitSet`.
+pub struct Iter<'a> {
+    words: &'a [u64],
+    /// Indices of the non-empty words.
+    nonempty: Bits<'a>,
+    /// Index of the first bit of `word`.
+    base: usize,
+    /// Bits of the current word which haven't been yielded yet.
+    word: u64,
+}
+
+impl Iterator for Iter<'_> {
+    type Item = usize;
+
+    #[inline]
+    fn next(&mut self) -> Option<usize> {
+        while self.word == 0 {
+            let index = self.nonempty.next()?;
+            self.base = index * 64;
+            self.word = self.words[index];
+        }
+        let bit = self.word.trailing_zeros() as usize;
+        self.word &= self.word - 1;
+        Some(self.base + bit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_unset() {
+        let mut set = BitSet::new();
+        assert!(set.is_empty());
+        assert!(set.set(5));
+        assert!(!set.set(5));
+        assert!(set.set(1_000_000));
+        assert!(set.contains(5) && set.contains(1_000_000));
+        assert!(!set.contains(6) && !set.contains(2_000_000));
+
+        assert!(set.unset(5));
+        assert!(!set.unset(5));
+        assert!(!set.unset(2_000_000));
+        assert!(!set.is_empty());
+        assert!(set.unset(1_000_000));
+        assert!(set.is_empty());
+
+        for index in 0..130 {
+            set.set(index);
+        }
+        set.unset(64);
+        assert_eq!(set.first_unset(), 64);
+        set.set(64);
+        assert_eq!(set.first_unset(), 130);
+    }
+
+    #[test]
+    fn iterate() {
+        let mut set = BitSet::new();
+        assert_eq!(set.iter().next(), None);
+
+        let indices = [0, 1, 63, 64, 4095, 4096, 262_143, 262_144, 999_999];
+        for &index in indices.iter().rev() {
+            set.set(index);
+        }
+        assert_eq!(set.iter().collect::<Vec<_>>(), indices);
+
+        // Emptied words drop out of the summary.
+        set.unset(4095);
+        set.unset(4096);
+        assert_eq!(
+            set.iter().collect::<Vec<_>>(),
+            [0, 1, 63, 64, 262_143, 262_144, 999_999]
+        );
+    }
+
+    #[test]
+    fn and() {
+        let mut a = BitSet::new();
+        let mut b = BitSet::new();
+        for index in (0..100_000).step_by(3) {
+            a.set(index);
+        }
+        for index in (0..50_000).step_by(5) {
+            b.set(index);
+        }
+        // A word which is non-empty in both, but shares no indices.
+        a.set(200_001);
+        b.set(200_002);
+
+        let both = a.and(&b);
+        let expected = (0..50_000).step_by(15).collect::<Vec<_>>();
+        assert_eq!(both.iter().collect::<Vec<_>>(), expected);
+        assert_eq!(b.and(&a).iter().collect::<Vec<_>>(), expected);
+        assert!(a.and(&BitSet::new()).is_empty());
+    }
+
+    #[test]
+    fn mapped() {
+        let mut set = BitSet::with_mapping(1 << 20);
+        assert!(set.set(5));
+        assert!(set.set((1 << 20) - 1));
+        assert!(set.contains(5));
+        assert_eq!(set.iter().collect::<Vec<_>>(), [5, (1 << 20) - 1]);
+        assert!(set.unset(5));
+
+        let mut other = BitSet::new();
+        other.set(5);
+        other.set((1 << 20) - 1);
+        assert_eq!(other.and(&set).iter().collect::<Vec<_>>(), [(1 << 20) - 1]);
+        assert_eq!(set.as_slice().to_bit_set().iter().count(), 1);
+    }
+}
@@ -1,3 +1,8 @@
+/// Marker for plain data types which can be moved around as raw bytes.
+///
+/// # Safety
+///
+/// Implementors must be valid for any bitwise copy of a valid value, and must not rely on `Drop`.
 pub unsafe trait Blit {}
 
 unsafe impl Blit for u8 {}
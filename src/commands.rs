@@ -0,0 +1,470 @@
+use std::{any::Any, ops::Range};
+
+use crate::{
+    blit::Blit,
+    registry::Registry,
+    world::{intern_shared_raw, ArchtypeKey, SharedId, ThingId, World},
+};
+
+type InternFn = unsafe fn(&mut World, usize, *const u8) -> SharedId;
+
+/// A part of a thing spawned by a command buffer, with its value stored in the buffer's data.
+enum SpawnPart {
+    Scalar {
+        part: usize,
+        offset: usize,
+    },
+    Vector {
+        part: usize,
+        offset: usize,
+        count: usize,
+    },
+    Shared {
+        part: usize,
+        offset: usize,
+        intern: InternFn,
+    },
+}
+
+enum Command {
+    Spawn {
+        thing: ThingId,
+        parts: Range<usize>,
+    },
+    Destroy(ThingId),
+    AddPart {
+        thing: ThingId,
+        part: usize,
+        offset: usize,
+    },
+    RemovePart {
+        thing: ThingId,
+        part: usize,
+    },
+}
+
+/// Records structural changes to a world, to be played back in order by `World::apply`. This
+/// lets systems request changes while queries are borrowing the world's chunks.
+///
+/// Things are spawned with ids reserved up front, so later commands in the same buffer, or parts
+/// like `Parent`, can refer to them before they exist.
+pub struct CommandBuffer<'registry> {
+    registry: &'registry Registry,
+    commands: Vec<Command>,
+    spawn_parts: Vec<SpawnPart>,
+    data: Vec<u8>,
+}
+
+impl<'registry> CommandBuffer<'registry> {
+    pub fn new(registry: &'registry Registry) -> Self {
+        Self {
+            registry,
+            commands: Vec::new(),
+            spawn_parts: Vec::new(),
+            data: Vec::new(),
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    /// Records spawning a thing with the id `thing`, which must have been reserved with
+    /// `World::reserve_thing` or a `ThingReserver`. If the buffer is dropped without being
+    /// applied, the id stays reserved unless it's released with `World::discard` or
+    /// `World::cancel_reservation`.
+    pub fn spawn(&mut self, thing: ThingId) -> SpawnCommand<'_, 'registry> {
+        let start = self.spawn_parts.len();
+        SpawnCommand {
+            commands: self,
+            thing,
+            key: ArchtypeKey::default(),
+            start,
+        }
+    }
+
+    pub fn destroy(&mut self, thing: ThingId) {
+        self.commands.push(Command::Destroy(thing));
+    }
+
+    pub fn add_part<T: Blit + Any>(&mut self, thing: ThingId, part: T) {
+        let index = self.part_index::<T>();
+        let offset = self.push_data(std::slice::from_ref(&part));
+        self.commands.push(Command::AddPart {
+            thing,
+            part: index,
+            offset,
+        });
+    }
+
+    pub fn remove_part<T: Blit + Any>(&mut self, thing: ThingId) {
+        let index = self.part_index::<T>();
+        self.commands
+            .push(Command::RemovePart { thing, part: index });
+    }
+
+    fn part_index<T: Any>(&self) -> usize {
+        self.registry
+            .part_index::<T>()
+            .expect("part type not registered")
+    }
+
+    /// Appends the bytes of `values` to the buffer's data, returning their offset.
+    fn push_data<T: Blit>(&mut self, values: &[T]) -> usize {
+        let offset = self.data.len();
+        let size = std::mem::size_of_val(values);
+        self.data.reserve(size);
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                values.as_ptr().cast::<u8>(),
+                self.data.as_mut_ptr().add(offset),
+                size,
+            );
+            self.data.set_len(offset + size);
+        }
+        offset
+    }
+
+    /// Plays back every recorded command against `world`, leaving the buffer empty.
+    pub(crate) fn apply(&mut self, world: &mut World) {
+        let Self {
+            commands,
+            spawn_parts,
+            data,
+            ..
+        } = self;
+
+        let mut parts = Vec::new();
+        let mut vectors = Vec::new();
+        let mut shared = Vec::new();
+        for command in commands.drain(..) {
+            match command {
+                Command::Spawn {
+                    thing,
+                    parts: range,
+                } => {
+                    let mut key = ArchtypeKey::default();
+                    parts.clear();
+                    vectors.clear();
+                    shared.clear();
+                    for spawn_part in &spawn_parts[range] {
+                        match *spawn_part {
+                            SpawnPart::Scalar { part, offset } => {
+                                key.scalar_parts.set(part);
+                                parts.push((part, offset));
+                            }
+                            SpawnPart::Vector {
+                                part,
+                                offset,
+                                count,
+                            } => {
+                                key.vector_parts.set(part);
+                                vectors.push((part, offset, count));
+                            }
+                            SpawnPart::Shared {
+                                part,
+                                offset,
+                                intern,
+                            } => {
+                                key.shared_parts.set(part);
+                                let id = unsafe { intern(world, part, data.as_ptr().add(offset)) };
+                                shared.push((part, id));
+                            }
+                        }
+                    }
+                    shared.sort_unstable_by_key(|&(part, _)| part);
+                    let shared = shared.iter().map(|&(_, id)| id).collect::<Vec<_>>();
+                    world.spawn(Some(thing), &key, &parts, &vectors, &shared, data);
+                }
+                Command::Destroy(thing) => {
+                    world.destroy(thing);
+                }
+                Command::AddPart {
+                    thing,
+                    part,
+                    offset,
+                } => unsafe {
+                    world.add_part_raw(thing, part, data.as_ptr().add(offset));
+                },
+                Command::RemovePart { thing, part } => {
+                    world.remove_part_raw(thing, part);
+                }
+            }
+        }
+        spawn_parts.clear();
+        data.clear();
+    }
+
+    /// Drops every recorded command without playing it back, cancelling the reservations of
+    /// things the buffer would have spawned.
+    pub(crate) fn discard(&mut self, world: &mut World) {
+        for command in self.commands.drain(..) {
+            if let Command::Spawn { thing, .. } = command {
+                world.cancel_reservation(thing);
+            }
+        }
+        self.spawn_parts.clear();
+        self.data.clear();
+    }
+}
+
+/// Builds up the parts of a thing to be spawned by a command buffer.
+pub struct SpawnCommand<'buffer, 'registry> {
+    commands: &'buffer mut CommandBuffer<'registry>,
+    thing: ThingId,
+    key: ArchtypeKey,
+    start: usize,
+}
+
+impl<'buffer, 'registry> SpawnCommand<'buffer, 'registry> {
+    pub fn add_part<T: Blit + Any>(mut self, part: T) -> Self {
+        let index = self.commands.part_index::<T>();
+        assert!(
+            !self.key.scalar_parts.contains(index),
+            "part added to thing more than once"
+        );
+        self.key.scalar_parts.set(index);
+
+        let offset = self.commands.push_data(std::slice::from_ref(&part));
+        self.commands.spawn_parts.push(SpawnPart::Scalar {
+            part: index,
+            offset,
+        });
+        self
+    }
+
+    pub fn add_vector_part<T: Blit + Any>(mut self, values: Vec<T>) -> Self {
+        let index = self.commands.part_index::<T>();
+        assert!(
+            !self.key.vector_parts.contains(index),
+            "vector part added to thing more than once"
+        );
+        self.key.vector_parts.set(index);
+
+        let offset = self.commands.push_data(&values);
+        self.commands.spawn_parts.push(SpawnPart::Vector {
+            part: index,
+            offset,
+            count: values.len(),
+        });
+        self
+    }
+
+    pub fn add_shared_part<T: Blit + Any + PartialEq>(mut self, part: T) -> Self {
+        let index = self.commands.part_index::<T>();
+        assert!(
+            !self.key.shared_parts.contains(index),
+            "shared part added to thing more than once"
+        );
+        self.key.shared_parts.set(index);
+
+        let offset = self.commands.push_data(std::slice::from_ref(&part));
+        self.commands.spawn_parts.push(SpawnPart::Shared {
+            part: index,
+            offset,
+            intern: intern_shared_raw::<T>,
+        });
+        self
+    }
+
+    /// Records the spawn, returning the thing's id.
+    pub fn finish(self) -> ThingId {
+        let parts = self.start..self.commands.spawn_parts.len();
+        self.commands.commands.push(Command::Spawn {
+            thing: self.thing,
+            parts,
+        });
+        self.thing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Copy, Clone, PartialEq, Debug)]
+    struct Position(f32, f32, f32);
+
+    #[derive(Copy, Clone, PartialEq, Debug)]
+    struct Health(u8);
+
+    #[derive(Copy, Clone, PartialEq, Debug)]
+    struct Team(u32);
+
+    unsafe impl Blit for Position {}
+    unsafe impl Blit for Health {}
+    unsafe impl Blit for Team {}
+
+    #[test]
+    fn apply_in_order() {
+        let mut registry = Registry::new();
+        registry.register_part::<Position>();
+        registry.register_part::<Health>();
+        registry.register_part::<Team>();
+        let mut world = World::new(&registry);
+
+        let things = (0..10)
+            .map(|i| world.factory().thing().add_part(Health(i as u8)).finish())
+            .collect::<Vec<_>>();
+        let reserved = (0..5).map(|_| world.reserve_thing()).collect::<Vec<_>>();
+        assert!(reserved.iter().all(|&thing| !world.contains(thing)));
+        assert!(!world.destroy(reserved[0]));
+
+        let mut commands = CommandBuffer::new(&registry);
+        let mut spawned = reserved.iter();
+        for health in world.query::<&mut Health>().iter() {
+            if health.0 % 2 == 0 {
+                let thing = *spawned.next().unwrap();
+                commands
+                    .spawn(thing)
+                    .add_part(Position(health.0 as f32, 0.0, 0.0))
+                    .add_vector_part(vec![Health(health.0); 3])
+                    .add_shared_part(Team(health.0 as u32 % 4))
+                    .finish();
+                commands.add_part(thing, Health(health.0));
+            } else {
+                health.0 += 100;
+            }
+        }
+        commands.add_part(things[1], Position(1.0, 1.0, 1.0));
+        commands.remove_part::<Health>(things[1]);
+        commands.destroy(things[3]);
+        commands.add_part(things[3], Position(3.0, 3.0, 3.0));
+        assert_eq!(commands.len(), 14);
+
+        world.apply(&mut commands);
+        assert!(commands.is_empty());
+
+        for (i, &thing) in reserved.iter().enumerate() {
+            let i = i * 2;
+            assert!(world.contains(thing));
+            assert_eq!(
+                world.get::<Position>(thing).as_deref(),
+                Some(&Position(i as f32, 0.0, 0.0))
+            );
+            assert_eq!(
+                world.get::<Health>(thing).as_deref(),
+                Some(&Health(i as u8))
+            );
+            assert_eq!(
+                world.get_vector_part::<Health>(thing).as_deref(),
+                Some(&[Health(i as u8); 3][..])
+            );
+            assert_eq!(
+                world.get_shared_part::<Team>(thing),
+                Some(&Team(i as u32 % 4))
+            );
+        }
+
+        assert_eq!(world.get::<Health>(things[1]).as_deref(), None);
+        assert_eq!(
+            world.get::<Position>(things[1]).as_deref(),
+            Some(&Position(1.0, 1.0, 1.0))
+        );
+        assert!(!world.contains(things[3]));
+        assert_eq!(
+            world.get::<Health>(things[5]).as_deref(),
+            Some(&Health(105))
+        );
+
+        // The buffer can be reused once applied.
+        commands.destroy(reserved[0]);
+        world.apply(&mut commands);
+        assert!(!world.contains(reserved[0]));
+    }
+
+    #[test]
+    #[should_panic(expected = "spawned thing id was not reserved")]
+    fn spawn_unreserved() {
+        let mut registry = Registry::new();
+        registry.register_part::<Health>();
+        let mut world = World::new(&registry);
+
+        let thing = world.factory().thing().add_part(Health(1)).finish();
+        let mut commands = CommandBuffer::new(&registry);
+        commands.spawn(thing).add_part(Health(2)).finish();
+        world.apply(&mut commands);
+    }
+
+    #[test]
+    fn discard() {
+        let mut registry = Registry::new();
+        registry.register_part::<Health>();
+        let mut world = World::new(&registry);
+
+        let mut commands = CommandBuffer::new(&registry);
+        let spawned = commands
+            .spawn(world.reserve_thing())
+            .add_part(Health(1))
+            .finish();
+        let existing = world.factory().thing().add_part(Health(2)).finish();
+        commands.destroy(existing);
+        world.discard(&mut commands);
+        assert!(commands.is_empty());
+        assert!(world.contains(existing) && !world.contains(spawned));
+
+        // The reservation was released, so the id can't be spawned or cancelled again.
+        assert!(!world.cancel_reservation(spawned));
+        let unused = world.reserve_thing();
+        assert!(world.cancel_reservation(unused));
+        assert!(!world.cancel_reservation(existing));
+        commands.spawn(spawned).add_part(Health(3)).finish();
+        let message =
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| world.apply(&mut commands)))
+                .unwrap_err();
+        assert_eq!(
+            message.downcast_ref::<&str>(),
+            Some(&"spawned thing id was not reserved")
+        );
+    }
+
+    #[test]
+    fn reserve_from_threads() {
+        let mut registry = Registry::new();
+        registry.register_part::<Health>();
+        let mut world = World::new(&registry);
+        let existing = world.factory().thing().add_part(Health(0)).finish();
+
+        let reserver = world.thing_reserver();
+        let mut buffers = std::thread::scope(|scope| {
+            let threads = (0..4u8)
+                .map(|i| {
+                    let registry = &registry;
+                    scope.spawn(move || {
+                        let mut commands = CommandBuffer::new(registry);
+                        for _ in 0..250 {
+                            commands
+                                .spawn(reserver.reserve())
+                                .add_part(Health(i + 1))
+                                .finish();
+                        }
+                        commands
+                    })
+                })
+                .collect::<Vec<_>>();
+            threads
+                .into_iter()
+                .map(|thread| thread.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+
+        // Ids reserved on other threads don't collide with ones allocated afterwards.
+        let spawned = world.factory().thing().add_part(Health(9)).finish();
+        for commands in &mut buffers {
+            world.apply(commands);
+        }
+
+        let mut counts = [0; 10];
+        for health in world.query::<&Health>().iter() {
+            counts[health.0 as usize] += 1;
+        }
+        assert_eq!(counts, [1, 250, 250, 250, 250, 0, 0, 0, 0, 1]);
+        assert!(world.contains(existing) && world.contains(spawned));
+    }
+}
@@ -1,10 +1,25 @@
-use crate::registry::Registry;
+use crate::{blit::Blit, registry::Registry};
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
 pub struct Link {
     hash: u128,
 }
 
+unsafe impl Blit for Link {}
+
+impl Link {
+    /// Creates a link to the asset with the given name, using a 128-bit FNV-1a hash of the name.
+    pub fn from_name(name: &str) -> Self {
+        const OFFSET_BASIS: u128 = 0x6c62272e07bb014262b821756295c58d;
+        const PRIME: u128 = 0x0000000001000000000000000000013b;
+        let hash = name.bytes().fold(OFFSET_BASIS, |hash, byte| {
+            (hash ^ byte as u128).wrapping_mul(PRIME)
+        });
+        Self { hash }
+    }
+}
+
+#[allow(dead_code)]
 pub struct Depot<'registry> {
     registry: &'registry Registry,
 }
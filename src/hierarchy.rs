@@ -0,0 +1,427 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    blit::Blit,
+    maths::{Quat, Vec3},
+    query::With,
+    registry::Registry,
+    world::{ThingId, World},
+};
+
+/// Attaches a thing to a parent, making its local transform parts relative to the parent.
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Parent(pub ThingId);
+
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct PosX(pub f32);
+
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct PosY(pub f32);
+
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct PosZ(pub f32);
+
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Orient(pub Quat);
+
+/// World space transform of a thing, written by `update_transforms`.
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct WorldTransform {
+    pub position: Vec3,
+    pub orient: Quat,
+}
+
+impl WorldTransform {
+    pub const IDENTITY: WorldTransform = WorldTransform {
+        position: Vec3::zero(),
+        orient: Quat::identity(),
+    };
+
+    /// Transforms `local`, relative to `self`, into the space `self` is relative to.
+    #[inline]
+    fn then(self, local: WorldTransform) -> WorldTransform {
+        WorldTransform {
+            position: self.position + self.orient.rotate(local.position),
+            orient: self.orient * local.orient,
+        }
+    }
+}
+
+unsafe impl Blit for Parent {}
+unsafe impl Blit for PosX {}
+unsafe impl Blit for PosY {}
+unsafe impl Blit for PosZ {}
+unsafe impl Blit for Orient {}
+unsafe impl Blit for WorldTransform {}
+
+/// Registers every part used by the hierarchy.
+pub fn register_parts(registry: &mut Registry) {
+    registry.register_part::<Parent>();
+    registry.register_part::<PosX>();
+    registry.register_part::<PosY>();
+    registry.register_part::<PosZ>();
+    registry.register_part::<Orient>();
+    registry.register_part::<WorldTransform>();
+}
+
+/// What happens to the children of a thing destroyed with `despawn`.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum DespawnPolicy {
+    /// Destroy every descendant along with the thing.
+    Cascade,
+    /// Remove the `Parent` part from the thing's children, turning them into roots.
+    Orphan,
+}
+
+/// Maps each parent to the things which link to it. Building the index takes a pass over every
+/// thing with a `Parent`, so it's built once and kept up to date by `despawn`, rather than rebuilt
+/// for each thing despawned. Parent links changed by anything else leave it stale: `despawn`
+/// skips listed children which have since moved to another parent, but misses children linked
+/// after the index was built.
+pub struct Children(HashMap<ThingId, Vec<ThingId>>);
+
+impl Children {
+    pub fn new(world: &World) -> Self {
+        let mut children = HashMap::<_, Vec<_>>::new();
+        for (child, parent) in world.query::<(ThingId, &Parent)>().iter() {
+            children.entry(parent.0).or_default().push(child);
+        }
+        Self(children)
+    }
+
+    /// Returns the things linking to `parent`.
+    pub fn of(&self, parent: ThingId) -> &[ThingId] {
+        self.0.get(&parent).map_or(&[], Vec::as_slice)
+    }
+
+    /// Forgets `thing`, both as a parent and as a child of its own parent. Returns the listed
+    /// children which still link to `thing`.
+    fn remove(&mut self, world: &World, thing: ThingId) -> Vec<ThingId> {
+        if let Some(parent) = world.get::<Parent>(thing) {
+            if let Some(siblings) = self.0.get_mut(&parent.0) {
+                siblings.retain(|&sibling| sibling != thing);
+            }
+        }
+        let mut children = self.0.remove(&thing).unwrap_or_default();
+        children.retain(|&child| world.get::<Parent>(child).as_deref() == Some(&Parent(thing)));
+        children
+    }
+}
+
+/// Destroys `thing`, dealing with its children according to `policy`, and updates `children` to
+/// match. Returns false if the id was stale.
+pub fn despawn(
+    world: &mut World,
+    children: &mut Children,
+    thing: ThingId,
+    policy: DespawnPolicy,
+) -> bool {
+    if !world.contains(thing) {
+        return false;
+    }
+
+    match policy {
+        DespawnPolicy::Cascade => {
+            // Track visited things so a cycle of parent links can't loop forever.
+            let mut visited = HashSet::new();
+            let mut stack = vec![thing];
+            while let Some(thing) = stack.pop() {
+                if visited.insert(thing) {
+                    stack.extend(children.remove(world, thing));
+                    world.destroy(thing);
+                }
+            }
+        }
+        DespawnPolicy::Orphan => {
+            for child in children.remove(world, thing) {
+                world.remove_part::<Parent>(child);
+            }
+            world.destroy(thing);
+        }
+    }
+    true
+}
+
+enum Resolve {
+    Pending,
+    Visiting,
+    /// `None` if the thing is part of, or descends from, a cycle.
+    Done(Option<WorldTransform>),
+}
+
+struct Node {
+    local: WorldTransform,
+    parent: Option<ThingId>,
+    resolve: Resolve,
+}
+
+/// Computes the `WorldTransform` of every thing which has one, from the local transform parts of
+/// the thing and its ancestors. Missing position parts count as zero and a missing `Orient` as
+/// the identity. A thing whose parent is stale, or has no `WorldTransform`, is treated as a root.
+///
+/// Returns the things left untouched because they're part of, or descend from, a cycle of
+/// `Parent` links.
+pub fn update_transforms(world: &mut World) -> Vec<ThingId> {
+    let mut order = Vec::new();
+    let mut nodes = HashMap::new();
+    let mut query = world.query_filtered::<(
+        ThingId,
+        Option<&PosX>,
+        Option<&PosY>,
+        Option<&PosZ>,
+        Option<&Orient>,
+        Option<&Parent>,
+    ), With<WorldTransform>>();
+    for (thing, x, y, z, orient, parent) in query.iter() {
+        let position = Vec3::new(
+            x.map_or(0.0, |x| x.0),
+            y.map_or(0.0, |y| y.0),
+            z.map_or(0.0, |z| z.0),
+        );
+        let local = WorldTransform {
+            position,
+            orient: orient.map_or(Quat::identity(), |orient| orient.0),
+        };
+        order.push(thing);
+        nodes.insert(
+            thing,
+            Node {
+                local,
+                parent: parent.map(|parent| parent.0),
+                resolve: Resolve::Pending,
+            },
+        );
+    }
+
+    // Walk up from each thing until we reach a root or an already resolved ancestor, then resolve
+    // the path back down, so every thing is visited a constant number of times. Reaching a thing
+    // that's still on the path means we've found a cycle.
+    let mut path = Vec::new();
+    for &thing in &order {
+        let mut current = thing;
+        let mut transform = loop {
+            let node = nodes.get_mut(&current).unwrap();
+            match node.resolve {
+                Resolve::Done(transform) => break transform,
+                Resolve::Visiting => break None,
+                Resolve::Pending => {}
+            }
+            node.resolve = Resolve::Visiting;
+            path.push(current);
+            match node.parent.filter(|parent| nodes.contains_key(parent)) {
+                Some(parent) => current = parent,
+                None => break Some(WorldTransform::IDENTITY),
+            }
+        };
+
+        for &thing in path.iter().rev() {
+            let node = nodes.get_mut(&thing).unwrap();
+            transform = transform.map(|parent| parent.then(node.local));
+            node.resolve = Resolve::Done(transform);
+        }
+        path.clear();
+    }
+
+    let mut cyclic = Vec::new();
+    for (thing, world_transform) in world.query::<(ThingId, &mut WorldTransform)>().iter() {
+        match nodes[&thing].resolve {
+            Resolve::Done(Some(transform)) => *world_transform = transform,
+            _ => cyclic.push(thing),
+        }
+    }
+    cyclic
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::FRAC_PI_2;
+
+    use super::*;
+
+    fn assert_near(a: Vec3, b: Vec3) {
+        assert!((a - b).length() < 1e-5, "{:?} != {:?}", a, b);
+    }
+
+    fn world_position(world: &World, thing: ThingId) -> Vec3 {
+        world.get::<WorldTransform>(thing).unwrap().position
+    }
+
+    #[test]
+    fn propagate() {
+        let mut registry = Registry::new();
+        register_parts(&mut registry);
+        let mut world = World::new(&registry);
+
+        let mut factory = world.factory();
+        let body = factory
+            .thing()
+            .add_part(PosX(10.0))
+            .add_part(Orient(Quat::from_axis_angle(
+                Vec3::new(0.0, 1.0, 0.0),
+                FRAC_PI_2,
+            )))
+            .add_part(WorldTransform::IDENTITY)
+            .finish();
+        let turret = factory
+            .thing()
+            .add_part(PosY(1.0))
+            .add_part(PosZ(2.0))
+            .add_part(Parent(body))
+            .add_part(WorldTransform::IDENTITY)
+            .finish();
+        let barrel = factory
+            .thing()
+            .add_part(PosZ(1.0))
+            .add_part(Parent(turret))
+            .add_part(WorldTransform::IDENTITY)
+            .finish();
+        // Children of things without a world transform are roots.
+        let hidden = factory.thing().add_part(PosX(100.0)).finish();
+        let detached = factory
+            .thing()
+            .add_part(PosX(1.0))
+            .add_part(Parent(hidden))
+            .add_part(WorldTransform::IDENTITY)
+            .finish();
+
+        assert!(update_transforms(&mut world).is_empty());
+        assert_near(world_position(&world, body), Vec3::new(10.0, 0.0, 0.0));
+        assert_near(world_position(&world, turret), Vec3::new(12.0, 1.0, 0.0));
+        assert_near(world_position(&world, barrel), Vec3::new(13.0, 1.0, 0.0));
+        assert_near(world_position(&world, detached), Vec3::new(1.0, 0.0, 0.0));
+
+        world.get_mut::<PosX>(body).unwrap().0 = 0.0;
+        update_transforms(&mut world);
+        assert_near(world_position(&world, barrel), Vec3::new(3.0, 1.0, 0.0));
+    }
+
+    #[test]
+    fn cycles() {
+        let mut registry = Registry::new();
+        register_parts(&mut registry);
+        let mut world = World::new(&registry);
+
+        let mut factory = world.factory();
+        let root = factory
+            .thing()
+            .add_part(PosX(1.0))
+            .add_part(WorldTransform::IDENTITY)
+            .finish();
+        let a = factory.thing().add_part(WorldTransform::IDENTITY).finish();
+        let b = factory
+            .thing()
+            .add_part(Parent(a))
+            .add_part(WorldTransform::IDENTITY)
+            .finish();
+        let c = factory
+            .thing()
+            .add_part(Parent(b))
+            .add_part(WorldTransform::IDENTITY)
+            .finish();
+        world.add_part(a, Parent(b));
+
+        let cyclic = update_transforms(&mut world);
+        assert_eq!(cyclic.len(), 3);
+        assert!(cyclic.contains(&a) && cyclic.contains(&b) && cyclic.contains(&c));
+        assert_near(world_position(&world, root), Vec3::new(1.0, 0.0, 0.0));
+
+        // Cascading through a cycle terminates.
+        let mut children = Children::new(&world);
+        assert!(despawn(
+            &mut world,
+            &mut children,
+            a,
+            DespawnPolicy::Cascade
+        ));
+        assert!(!world.contains(a) && !world.contains(b) && !world.contains(c));
+        assert!(world.contains(root));
+    }
+
+    #[test]
+    fn despawn_policies() {
+        let mut registry = Registry::new();
+        register_parts(&mut registry);
+        let mut world = World::new(&registry);
+
+        let mut factory = world.factory();
+        let root = factory.thing().add_part(PosX(1.0)).finish();
+        let child = factory.thing().add_part(Parent(root)).finish();
+        let grandchild = factory.thing().add_part(Parent(child)).finish();
+        let other = factory.thing().add_part(Parent(root)).finish();
+
+        let mut children = Children::new(&world);
+        assert_eq!(children.of(child), &[grandchild]);
+
+        assert!(despawn(
+            &mut world,
+            &mut children,
+            child,
+            DespawnPolicy::Orphan
+        ));
+        assert!(!world.contains(child));
+        assert_eq!(world.get::<Parent>(grandchild).as_deref(), None);
+        assert_eq!(world.get::<Parent>(other).as_deref(), Some(&Parent(root)));
+        assert_eq!(children.of(root), &[other]);
+        assert!(children.of(child).is_empty());
+
+        // The same index serves later despawns.
+        assert!(despawn(
+            &mut world,
+            &mut children,
+            root,
+            DespawnPolicy::Cascade
+        ));
+        assert!(!world.contains(root) && !world.contains(other));
+        assert!(world.contains(grandchild));
+        assert!(children.of(root).is_empty());
+        assert!(!despawn(
+            &mut world,
+            &mut children,
+            root,
+            DespawnPolicy::Cascade
+        ));
+    }
+
+    #[test]
+    fn despawn_reparented() {
+        let mut registry = Registry::new();
+        register_parts(&mut registry);
+        let mut world = World::new(&registry);
+
+        let mut factory = world.factory();
+        let old = factory.thing().add_part(PosX(1.0)).finish();
+        let new = factory.thing().add_part(PosX(2.0)).finish();
+        let moved = factory.thing().add_part(Parent(old)).finish();
+        let kept = factory.thing().add_part(Parent(old)).finish();
+        let mut children = Children::new(&world);
+
+        // The index still lists `moved` under its old parent, but despawning follows the links.
+        *world.get_mut::<Parent>(moved).unwrap() = Parent(new);
+        assert_eq!(children.of(old), &[moved, kept]);
+        assert!(despawn(
+            &mut world,
+            &mut children,
+            old,
+            DespawnPolicy::Cascade
+        ));
+        assert!(world.contains(moved) && !world.contains(kept));
+
+        let orphan = world.factory().thing().add_part(Parent(new)).finish();
+        let mut children = Children::new(&world);
+        world.add_part(orphan, Parent(moved));
+        assert!(despawn(
+            &mut world,
+            &mut children,
+            new,
+            DespawnPolicy::Orphan
+        ));
+        assert_eq!(world.get::<Parent>(orphan).as_deref(), Some(&Parent(moved)));
+        assert_eq!(world.get::<Parent>(moved).as_deref(), None);
+    }
+}
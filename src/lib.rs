@@ -0,0 +1,17 @@
+mod bit_set;
+pub mod blit;
+pub mod commands;
+pub mod depot;
+mod helpers;
+pub mod hierarchy;
+pub mod maths;
+pub mod query;
+mod raw_table;
+pub mod registry;
+pub mod ring_buf;
+pub mod schedule;
+pub mod sparse_vec;
+pub mod thread_pool;
+mod vector;
+mod virtual_vec;
+pub mod world;
@@ -1,48 +1,18 @@
-mod blit;
-mod depot;
-mod helpers;
-mod maths;
-mod raw_table;
-mod registry;
-mod ring_buf;
-mod sparse_vec;
-mod virtual_vec;
-mod world;
-
 use std::{thread::sleep, time::Duration};
 
-use blit::Blit;
-use depot::Link;
-use maths::{Quat, Vec3};
-use world::{ThingId, World};
-
-use crate::{depot::Depot, registry::Registry};
-
-#[repr(C)]
-#[derive(Copy, Clone)]
-struct PosX(f32);
-
-#[repr(C)]
-#[derive(Copy, Clone)]
-struct PosY(f32);
+use bits::{
+    blit::Blit,
+    depot::{Depot, Link},
+    hierarchy::{self, Orient, Parent, PosX, PosY, PosZ, WorldTransform},
+    maths::{Quat, Vec3},
+    registry::Registry,
+    world::World,
+};
 
 #[repr(C)]
-#[derive(Copy, Clone)]
-struct PosZ(f32);
-
-#[repr(C)]
-#[derive(Copy, Clone)]
-struct Orient(Quat);
-
-#[repr(C)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq)]
 struct Mesh(Link);
 
-#[repr(C)]
-#[derive(Copy, Clone)]
-struct Parent(ThingId);
-
-#[derive(Default)]
 struct Turret {
     azimuth: f32,
     azimuth_max: f32,
@@ -52,64 +22,91 @@ struct Turret {
     elevation_max: f32,
 }
 
-unsafe impl Blit for PosX {}
-unsafe impl Blit for PosY {}
-unsafe impl Blit for PosZ {}
-unsafe impl Blit for Orient {}
-unsafe impl Blit for Parent {}
+unsafe impl Blit for Mesh {}
 unsafe impl Blit for Turret {}
 
+impl Turret {
+    fn new(azimuth_min: f32, azimuth_max: f32, elevation_min: f32, elevation_max: f32) -> Self {
+        Self {
+            azimuth: 0.0,
+            azimuth_max,
+            azimuth_min,
+            elevation: 0.0,
+            elevation_min,
+            elevation_max,
+        }
+    }
+
+    /// Turns the turret by `step` radians, wrapping back to the start of its arc at the end, and
+    /// returns its orientation.
+    fn sweep(&mut self, step: f32) -> Quat {
+        self.azimuth += step;
+        if self.azimuth > self.azimuth_max {
+            self.azimuth = self.azimuth_min;
+        }
+        self.elevation = self.elevation.clamp(self.elevation_min, self.elevation_max);
+        Quat::from_axis_angle(Vec3::new(0.0, 1.0, 0.0), self.azimuth)
+            * Quat::from_axis_angle(Vec3::new(1.0, 0.0, 0.0), self.elevation)
+    }
+}
+
 fn main() {
     println!("Hello, world!");
 
     let mut registry = Registry::new();
-    registry.register_part::<PosX>();
-    registry.register_part::<PosY>();
-    registry.register_part::<PosZ>();
-    registry.register_part::<Orient>();
-    registry.register_part::<Parent>();
+    hierarchy::register_parts(&mut registry);
+    registry.register_part::<Mesh>();
     registry.register_part::<Turret>();
 
     let _depot = Depot::new(&registry);
-    let _world = World::new(&registry);
-
-    // let mut factory = world.factory();
-    // let body = factory
-    //     .thing()
-    //     .add_shared_part(Mesh)
-    //     .add_part(PosX(10.0))
-    //     .add_part(PosY(0.0))
-    //     .add_part(PosZ(0.0))
-    //     .finish();
-
-    // let left_track = factory()
-    //     .thing()
-    //     .add_part(PosZ(-1.0))
-    //     .add_part(Parent(body))
-    //     .finish();
-
-    // let right_track = factory()
-    //     .thing()
-    //     .add_part(PosZ(1.0))
-    //     .add_part(Parent(body))
-    //     .finish();
-
-    // let main_turret = factory()
-    //     .thing()
-    //     .add_part(PosY(0.25))
-    //     .add_part(Parent(body))
-    //     .add_part(Turret::default())
-    //     .finish();
-
-    // let machinegun = factory()
-    //     .thing()
-    //     .add_part(PosY(0.1))
-    //     .add_part(PosZ(0.1))
-    //     .add_part(Parent(body))
-    //     .add_part(Turret::default())
-    //     .finish();
+    let mut world = World::new(&registry);
+
+    let mut factory = world.factory();
+    let body = factory
+        .thing()
+        .add_shared_part(Mesh(Link::from_name("tank_body")))
+        .add_part(PosX(10.0))
+        .add_part(PosY(0.0))
+        .add_part(PosZ(0.0))
+        .add_part(WorldTransform::IDENTITY)
+        .finish();
+
+    let _left_track = factory
+        .thing()
+        .add_part(PosZ(-1.0))
+        .add_part(Parent(body))
+        .finish();
+
+    let _right_track = factory
+        .thing()
+        .add_part(PosZ(1.0))
+        .add_part(Parent(body))
+        .finish();
+
+    let _main_turret = factory
+        .thing()
+        .add_part(PosY(0.25))
+        .add_part(Parent(body))
+        .add_part(Turret::new(-3.0, 3.0, -0.1, 0.35))
+        .add_part(Orient(Quat::identity()))
+        .add_part(WorldTransform::IDENTITY)
+        .finish();
+
+    let _machinegun = factory
+        .thing()
+        .add_part(PosY(0.1))
+        .add_part(PosZ(0.1))
+        .add_part(Parent(body))
+        .add_part(Turret::new(-0.8, 0.8, -0.2, 0.6))
+        .add_part(Orient(Quat::identity()))
+        .add_part(WorldTransform::IDENTITY)
+        .finish();
 
     loop {
+        for (turret, orient) in world.query::<(&mut Turret, &mut Orient)>().iter() {
+            orient.0 = turret.sweep(0.1);
+        }
+        hierarchy::update_transforms(&mut world);
         sleep(Duration::from_secs(1))
     }
 }
@@ -7,6 +7,63 @@ pub struct Quat {
     w: f32,
 }
 
+impl Quat {
+    #[inline]
+    pub const fn new(x: f32, y: f32, z: f32, w: f32) -> Self {
+        Self { x, y, z, w }
+    }
+
+    #[inline]
+    pub const fn identity() -> Self {
+        Self {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+            w: 1.0,
+        }
+    }
+
+    /// Rotation of `angle` radians about the normalized `axis`.
+    #[inline]
+    pub fn from_axis_angle(axis: Vec3, angle: f32) -> Self {
+        let (sin, cos) = (angle * 0.5).sin_cos();
+        Self {
+            x: axis.x * sin,
+            y: axis.y * sin,
+            z: axis.z * sin,
+            w: cos,
+        }
+    }
+
+    #[inline]
+    fn vector(self) -> Vec3 {
+        Vec3::new(self.x, self.y, self.z)
+    }
+
+    /// Rotates `v` by this quaternion, which must be normalized.
+    #[inline]
+    pub fn rotate(self, v: Vec3) -> Vec3 {
+        let u = self.vector();
+        let t = u.cross(v) * 2.0;
+        v + t * self.w + u.cross(t)
+    }
+}
+
+impl std::ops::Mul for Quat {
+    type Output = Quat;
+    /// Composes two rotations, applying `rhs` first.
+    fn mul(self, rhs: Self) -> Self::Output {
+        Self {
+            x: self.w * rhs.x + self.x * rhs.w + self.y * rhs.z - self.z * rhs.y,
+            y: self.w * rhs.y - self.x * rhs.z + self.y * rhs.w + self.z * rhs.x,
+            z: self.w * rhs.z + self.x * rhs.y - self.y * rhs.x + self.z * rhs.w,
+            w: self.w * rhs.w - self.x * rhs.x - self.y * rhs.y - self.z * rhs.z,
+        }
+    }
+}
+
+unsafe impl crate::blit::Blit for Quat {}
+
 #[derive(Copy, Clone, PartialEq, Debug)]
 #[repr(C)]
 pub struct Vec3 {
@@ -33,9 +90,9 @@ impl Vec3 {
     #[inline]
     pub const fn zero() -> Self {
         Self {
-            x: 1.0,
-            y: 1.0,
-            z: 1.0,
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
         }
     }
 
@@ -52,6 +109,31 @@ impl Vec3 {
     pub fn dot(self, rhs: Self) -> f32 {
         self.x * rhs.x + self.y * rhs.y + self.z * rhs.z
     }
+
+    #[inline]
+    pub fn cross(self, rhs: Self) -> Self {
+        Self {
+            x: self.y * rhs.z - self.z * rhs.y,
+            y: self.z * rhs.x - self.x * rhs.z,
+            z: self.x * rhs.y - self.y * rhs.x,
+        }
+    }
+
+    #[inline]
+    pub fn length(self) -> f32 {
+        self.dot(self).sqrt()
+    }
+}
+
+impl std::ops::Mul<f32> for Vec3 {
+    type Output = Vec3;
+    fn mul(self, rhs: f32) -> Self::Output {
+        Self {
+            x: self.x * rhs,
+            y: self.y * rhs,
+            z: self.z * rhs,
+        }
+    }
 }
 
 impl std::ops::Add for Vec3 {
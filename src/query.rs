@@ -0,0 +1,1896 @@
+use std::{any::Any, marker::PhantomData};
+
+use crate::{
+    bit_set::{self, BitSet},
+    blit::Blit,
+    registry::Registry,
+    thread_pool::ThreadPool,
+    vector::RawVector,
+    world::{Archtype, BorrowFlag, Chunk, PartBitmap, ThingId, World},
+};
+
+/// The set of parts a query requires or excludes, and which parts it reads or writes.
+///
+/// Vector and shared parts are required separately, but share read and write tracking with scalar
+/// parts of the same type. Sparse parts aren't part of any archtype, so are checked row by row
+/// instead of being required or excluded.
+#[derive(Clone, Default)]
+pub struct Access {
+    pub(crate) required: PartBitmap,
+    pub(crate) required_vectors: PartBitmap,
+    pub(crate) required_shared: PartBitmap,
+    pub(crate) excluded: PartBitmap,
+    pub(crate) sparse: PartBitmap,
+    /// The sparse parts every matched row has, whose occupancy bounds the rows visited.
+    pub(crate) required_sparse: PartBitmap,
+    pub(crate) reads: PartBitmap,
+    pub(crate) writes: PartBitmap,
+}
+
+impl Access {
+    fn add_read<T: Any>(&mut self, part: usize) {
+        if self.writes.contains(part) {
+            conflicting_access::<T>()
+        }
+        self.reads.set(part);
+    }
+
+    fn add_write<T: Any>(&mut self, part: usize) {
+        if self.reads.contains(part) || self.writes.contains(part) {
+            conflicting_access::<T>()
+        }
+        self.writes.set(part);
+    }
+
+    /// Records a read made by a filter. A query may filter on a part it also writes, in which case
+    /// its write already covers the read.
+    fn add_filter_read(&mut self, part: usize) {
+        if !self.writes.contains(part) {
+            self.reads.set(part);
+        }
+    }
+
+    #[inline]
+    fn matches(&self, archtype: &Archtype) -> bool {
+        let parts = &archtype.key.scalar_parts;
+        parts.is_superset_of(&self.required)
+            && parts.is_disjoint_from(&self.excluded)
+            && archtype
+                .key
+                .vector_parts
+                .is_superset_of(&self.required_vectors)
+            && archtype
+                .key
+                .shared_parts
+                .is_superset_of(&self.required_shared)
+    }
+}
+
+#[cold]
+#[inline(never)]
+fn conflicting_access<T: Any>() {
+    panic!(
+        "query accesses part `{}` mutably more than once",
+        std::any::type_name::<T>()
+    );
+}
+
+fn registered_part<T: Any>(registry: &Registry) -> usize {
+    registry
+        .part_index::<T>()
+        .expect("part type not registered")
+}
+
+/// Returns the index of the part `T`, which must have data, rather than being a tag.
+fn data_part<T: Any>(registry: &Registry) -> usize {
+    let part = registered_part::<T>(registry);
+    if registry.part(part).is_tag() {
+        tag_without_data::<T>()
+    }
+    part
+}
+
+/// Returns the index of the part `T`, which must have change ticks, so can't be a tag or sparse.
+fn tracked_part<T: Any>(registry: &Registry) -> usize {
+    let part = data_part::<T>(registry);
+    if registry.part(part).is_sparse() {
+        sparse_without_ticks::<T>()
+    }
+    part
+}
+
+/// Requires the scalar part `part` for a match, either by archtype or, for sparse parts, row by
+/// row.
+fn require_part(registry: &Registry, access: &mut Access, part: usize) {
+    if registry.part(part).is_sparse() {
+        access.sparse.set(part);
+        access.required_sparse.set(part);
+    } else {
+        access.required.set(part);
+    }
+}
+
+#[cold]
+#[inline(never)]
+fn sparse_without_ticks<T: Any>() {
+    panic!(
+        "`Changed` can't filter on sparse part `{}`, which has no change ticks",
+        std::any::type_name::<T>()
+    );
+}
+
+#[cold]
+#[inline(never)]
+fn tag_without_data<T: Any>() {
+    panic!(
+        "tag `{}` has no data to fetch or track changes to, filter on it with `With` or `Without`",
+        std::any::type_name::<T>()
+    );
+}
+
+/// Offsets of a part's data and change tick columns, and of the tick for the column as a whole,
+/// within an archtype's chunks.
+#[derive(Copy, Clone)]
+pub struct ColumnState {
+    offset: usize,
+    ticks: usize,
+    chunk_tick: usize,
+}
+
+impl ColumnState {
+    fn prepare<T: Any>(registry: &Registry, archtype: &Archtype) -> Option<Self> {
+        let column = archtype.column(registered_part::<T>(registry))?;
+        Some(Self {
+            offset: column.offset,
+            ticks: column.ticks,
+            chunk_tick: column.chunk_tick,
+        })
+    }
+
+    fn prepare_vector<T: Any>(registry: &Registry, archtype: &Archtype) -> Option<Self> {
+        let column = archtype.vector_column(registered_part::<T>(registry))?;
+        Some(Self {
+            offset: column.offset,
+            ticks: column.ticks,
+            chunk_tick: column.chunk_tick,
+        })
+    }
+
+    #[inline]
+    unsafe fn data<T>(self, chunk: *const Chunk) -> *mut T {
+        (chunk as *mut u8).add(self.offset).cast()
+    }
+
+    #[inline]
+    unsafe fn ticks(self, chunk: *const Chunk) -> *mut u32 {
+        (chunk as *mut u8).add(self.ticks).cast()
+    }
+
+    #[inline]
+    unsafe fn chunk_tick(self, chunk: *const Chunk) -> *mut u32 {
+        (chunk as *mut u8).add(self.chunk_tick).cast()
+    }
+}
+
+/// Locates the values of a sparse part for the rows of a chunk, through the chunk's thing ids.
+pub struct SparseState<T> {
+    ids: usize,
+    values: *mut T,
+    /// Occupancy bitmap words of the part's storage.
+    pop: *const [u64],
+}
+
+impl<T> Clone for SparseState<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for SparseState<T> {}
+
+impl<T: Any> SparseState<T> {
+    /// Returns `None` if `T` is stored in chunks.
+    fn prepare(world: &World, archtype: &Archtype) -> Option<Self> {
+        let set = world.sparse_set(registered_part::<T>(world.registry))?;
+        // The storage can't change while the world is borrowed by the query.
+        Some(Self {
+            ids: archtype.layout.ids,
+            values: set.as_ptr().cast(),
+            pop: set.occupancy().words(),
+        })
+    }
+
+    #[inline]
+    unsafe fn get(self, chunk: *const Chunk, row: usize) -> Option<*mut T> {
+        let slot = (chunk as *const u8)
+            .add(self.ids)
+            .cast::<ThingId>()
+            .add(row)
+            .read()
+            .slot();
+        let word = (&*self.pop).get(slot / 64)?;
+        if word & (1 << (slot % 64)) != 0 {
+            Some(self.values.add(slot))
+        } else {
+            None
+        }
+    }
+}
+
+/// Where a scalar part's values are for an archtype, either in a column of its chunks or in the
+/// part's sparse storage.
+pub enum PartState<T> {
+    Column(ColumnState),
+    Sparse(SparseState<T>),
+}
+
+impl<T> Clone for PartState<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for PartState<T> {}
+
+impl<T: Any> PartState<T> {
+    fn prepare(world: &World, archtype: &Archtype) -> Option<Self> {
+        match SparseState::prepare(world, archtype) {
+            Some(sparse) => Some(PartState::Sparse(sparse)),
+            None => Some(PartState::Column(ColumnState::prepare::<T>(
+                world.registry,
+                archtype,
+            )?)),
+        }
+    }
+
+    #[inline]
+    unsafe fn matches_row(self, chunk: *const Chunk, row: usize) -> bool {
+        match self {
+            PartState::Column(_) => true,
+            PartState::Sparse(sparse) => sparse.get(chunk, row).is_some(),
+        }
+    }
+}
+
+#[cold]
+#[inline(never)]
+fn sparse_chunk() -> ! {
+    unreachable!("sparse parts can't be fetched a chunk at a time")
+}
+
+/// Something that can be fetched from the archtypes matched by a query, either a chunk or a row
+/// at a time.
+///
+/// # Safety
+///
+/// `access` must declare every part touched by `fetch_chunk` and `fetch_row`, and declare it as a
+/// write if the fetched data allows mutation.
+pub unsafe trait Fetch {
+    /// The data fetched from a single chunk, typically column slices.
+    type Chunk<'a>;
+    /// The data fetched from a single row.
+    type Item<'a>;
+    /// Per-archtype state used to locate data within that archtype's chunks.
+    type State: Copy;
+
+    fn access(registry: &Registry, access: &mut Access);
+
+    /// Returns `None` if the archtype doesn't contain the fetched data.
+    fn prepare(world: &World, archtype: &Archtype) -> Option<Self::State>;
+
+    /// Returns false if the row lacks data which can't be ruled out by archtype, like a sparse
+    /// part.
+    ///
+    /// # Safety
+    ///
+    /// `chunk` must belong to the archtype `state` was prepared for, and `row` must be less than
+    /// the chunk's length.
+    #[inline]
+    unsafe fn matches_row(_state: Self::State, _chunk: *const Chunk, _row: usize) -> bool {
+        true
+    }
+
+    /// # Safety
+    ///
+    /// `chunk` must belong to the archtype `state` was prepared for, and nothing else may hold a
+    /// conflicting borrow of the chunk's columns for `'a`.
+    unsafe fn fetch_chunk<'a>(state: Self::State, chunk: *mut Chunk, tick: u32) -> Self::Chunk<'a>;
+
+    /// # Safety
+    ///
+    /// As for `fetch_chunk`, and additionally `row` must be less than the chunk's length.
+    unsafe fn fetch_row<'a>(
+        state: Self::State,
+        chunk: *mut Chunk,
+        row: usize,
+        tick: u32,
+    ) -> Self::Item<'a>;
+}
+
+unsafe impl<T: Blit + Any> Fetch for &T {
+    type Chunk<'a> = &'a [T];
+    type Item<'a> = &'a T;
+    type State = PartState<T>;
+
+    fn access(registry: &Registry, access: &mut Access) {
+        let part = data_part::<T>(registry);
+        require_part(registry, access, part);
+        access.add_read::<T>(part);
+    }
+
+    fn prepare(world: &World, archtype: &Archtype) -> Option<PartState<T>> {
+        PartState::prepare(world, archtype)
+    }
+
+    #[inline]
+    unsafe fn matches_row(state: PartState<T>, chunk: *const Chunk, row: usize) -> bool {
+        state.matches_row(chunk, row)
+    }
+
+    #[inline]
+    unsafe fn fetch_chunk<'a>(state: PartState<T>, chunk: *mut Chunk, _tick: u32) -> &'a [T] {
+        match state {
+            PartState::Column(state) => {
+                std::slice::from_raw_parts(state.data(chunk), (*chunk).len as usize)
+            }
+            PartState::Sparse(_) => sparse_chunk(),
+        }
+    }
+
+    #[inline]
+    unsafe fn fetch_row<'a>(
+        state: PartState<T>,
+        chunk: *mut Chunk,
+        row: usize,
+        _tick: u32,
+    ) -> &'a T {
+        match state {
+            PartState::Column(state) => &*state.data::<T>(chunk).add(row),
+            PartState::Sparse(sparse) => &*sparse.get(chunk, row).unwrap(),
+        }
+    }
+}
+
+unsafe impl<T: Blit + Any> Fetch for &mut T {
+    type Chunk<'a> = &'a mut [T];
+    type Item<'a> = &'a mut T;
+    type State = PartState<T>;
+
+    fn access(registry: &Registry, access: &mut Access) {
+        let part = data_part::<T>(registry);
+        require_part(registry, access, part);
+        access.add_write::<T>(part);
+    }
+
+    fn prepare(world: &World, archtype: &Archtype) -> Option<PartState<T>> {
+        PartState::prepare(world, archtype)
+    }
+
+    #[inline]
+    unsafe fn matches_row(state: PartState<T>, chunk: *const Chunk, row: usize) -> bool {
+        state.matches_row(chunk, row)
+    }
+
+    #[inline]
+    unsafe fn fetch_chunk<'a>(state: PartState<T>, chunk: *mut Chunk, tick: u32) -> &'a mut [T] {
+        match state {
+            PartState::Column(state) => {
+                let len = (*chunk).len as usize;
+                std::slice::from_raw_parts_mut(state.ticks(chunk), len).fill(tick);
+                state.chunk_tick(chunk).write(tick);
+                std::slice::from_raw_parts_mut(state.data(chunk), len)
+            }
+            PartState::Sparse(_) => sparse_chunk(),
+        }
+    }
+
+    /// Sparse parts have no change ticks, so writes to them aren't tracked.
+    #[inline]
+    unsafe fn fetch_row<'a>(
+        state: PartState<T>,
+        chunk: *mut Chunk,
+        row: usize,
+        tick: u32,
+    ) -> &'a mut T {
+        match state {
+            PartState::Column(state) => {
+                state.ticks(chunk).add(row).write(tick);
+                state.chunk_tick(chunk).write(tick);
+                &mut *state.data::<T>(chunk).add(row)
+            }
+            PartState::Sparse(sparse) => &mut *sparse.get(chunk, row).unwrap(),
+        }
+    }
+}
+
+/// Fetches the id of each thing. Matches every archtype and needs no access to any part.
+unsafe impl Fetch for ThingId {
+    type Chunk<'a> = &'a [ThingId];
+    type Item<'a> = ThingId;
+    /// Offset of the thing id column.
+    type State = usize;
+
+    fn access(_registry: &Registry, _access: &mut Access) {}
+
+    fn prepare(_world: &World, archtype: &Archtype) -> Option<usize> {
+        Some(archtype.layout.ids)
+    }
+
+    #[inline]
+    unsafe fn fetch_chunk<'a>(offset: usize, chunk: *mut Chunk, _tick: u32) -> &'a [ThingId] {
+        let ids = (chunk as *const u8).add(offset).cast();
+        std::slice::from_raw_parts(ids, (*chunk).len as usize)
+    }
+
+    #[inline]
+    unsafe fn fetch_row<'a>(
+        offset: usize,
+        chunk: *mut Chunk,
+        row: usize,
+        _tick: u32,
+    ) -> Self::Item<'a> {
+        (chunk as *const u8)
+            .add(offset)
+            .cast::<ThingId>()
+            .add(row)
+            .read()
+    }
+}
+
+/// The vector parts of type `T` for every row in a chunk.
+pub struct Vectors<'a, T> {
+    vectors: &'a [RawVector],
+    _marker: PhantomData<&'a [T]>,
+}
+
+impl<'a, T> Vectors<'a, T> {
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.vectors.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.vectors.is_empty()
+    }
+
+    #[inline]
+    pub fn get(&self, row: usize) -> Option<&'a [T]> {
+        let vector = self.vectors.get(row)?;
+        unsafe { Some(vector.as_slice()) }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &'a [T]> {
+        let vectors = self.vectors;
+        vectors.iter().map(|vector| unsafe { vector.as_slice() })
+    }
+}
+
+// SAFETY: only shared slices of `T` are handed out, and the vectors aren't modified while they're
+// borrowed.
+unsafe impl<T: Sync> Send for Vectors<'_, T> {}
+
+/// The mutable vector parts of type `T` for every row in a chunk.
+pub struct VectorsMut<'a, T> {
+    vectors: &'a mut [RawVector],
+    _marker: PhantomData<&'a mut [T]>,
+}
+
+// SAFETY: the vectors are borrowed exclusively, so `T` values are only moved between threads.
+unsafe impl<T: Send> Send for VectorsMut<'_, T> {}
+
+impl<'a, T> VectorsMut<'a, T> {
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.vectors.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.vectors.is_empty()
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self, row: usize) -> Option<&mut [T]> {
+        let vector = self.vectors.get_mut(row)?;
+        unsafe { Some(vector.as_mut_slice()) }
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut [T]> {
+        self.vectors
+            .iter_mut()
+            .map(|vector| unsafe { vector.as_mut_slice() })
+    }
+}
+
+unsafe impl<T: Blit + Any> Fetch for &[T] {
+    type Chunk<'a> = Vectors<'a, T>;
+    type Item<'a> = &'a [T];
+    type State = ColumnState;
+
+    fn access(registry: &Registry, access: &mut Access) {
+        let part = registered_part::<T>(registry);
+        access.required_vectors.set(part);
+        access.add_read::<T>(part);
+    }
+
+    fn prepare(world: &World, archtype: &Archtype) -> Option<ColumnState> {
+        ColumnState::prepare_vector::<T>(world.registry, archtype)
+    }
+
+    #[inline]
+    unsafe fn fetch_chunk<'a>(state: ColumnState, chunk: *mut Chunk, _tick: u32) -> Vectors<'a, T> {
+        Vectors {
+            vectors: std::slice::from_raw_parts(state.data(chunk), (*chunk).len as usize),
+            _marker: PhantomData,
+        }
+    }
+
+    #[inline]
+    unsafe fn fetch_row<'a>(
+        state: ColumnState,
+        chunk: *mut Chunk,
+        row: usize,
+        _tick: u32,
+    ) -> &'a [T] {
+        (*state.data::<RawVector>(chunk).add(row)).as_slice()
+    }
+}
+
+unsafe impl<T: Blit + Any> Fetch for &mut [T] {
+    type Chunk<'a> = VectorsMut<'a, T>;
+    type Item<'a> = &'a mut [T];
+    type State = ColumnState;
+
+    fn access(registry: &Registry, access: &mut Access) {
+        let part = registered_part::<T>(registry);
+        access.required_vectors.set(part);
+        access.add_write::<T>(part);
+    }
+
+    fn prepare(world: &World, archtype: &Archtype) -> Option<ColumnState> {
+        ColumnState::prepare_vector::<T>(world.registry, archtype)
+    }
+
+    #[inline]
+    unsafe fn fetch_chunk<'a>(
+        state: ColumnState,
+        chunk: *mut Chunk,
+        tick: u32,
+    ) -> VectorsMut<'a, T> {
+        let len = (*chunk).len as usize;
+        std::slice::from_raw_parts_mut(state.ticks(chunk), len).fill(tick);
+        state.chunk_tick(chunk).write(tick);
+        VectorsMut {
+            vectors: std::slice::from_raw_parts_mut(state.data(chunk), len),
+            _marker: PhantomData,
+        }
+    }
+
+    #[inline]
+    unsafe fn fetch_row<'a>(
+        state: ColumnState,
+        chunk: *mut Chunk,
+        row: usize,
+        tick: u32,
+    ) -> &'a mut [T] {
+        state.ticks(chunk).add(row).write(tick);
+        state.chunk_tick(chunk).write(tick);
+        (*state.data::<RawVector>(chunk).add(row)).as_mut_slice()
+    }
+}
+
+/// Fetches the shared part `T`, which has a single value for every row in a chunk. Iterating over
+/// chunks lets callers group work, like rendering, by shared value.
+pub struct Shared<T>(PhantomData<T>);
+
+unsafe impl<T: Blit + Any> Fetch for Shared<T> {
+    type Chunk<'a> = &'a T;
+    type Item<'a> = &'a T;
+    /// Offset of the shared value in the chunk header.
+    type State = usize;
+
+    fn access(registry: &Registry, access: &mut Access) {
+        let part = registered_part::<T>(registry);
+        access.required_shared.set(part);
+        access.add_read::<T>(part);
+    }
+
+    fn prepare(world: &World, archtype: &Archtype) -> Option<usize> {
+        let column = archtype.shared_column(registered_part::<T>(world.registry))?;
+        Some(column.offset)
+    }
+
+    #[inline]
+    unsafe fn fetch_chunk<'a>(offset: usize, chunk: *mut Chunk, _tick: u32) -> &'a T {
+        &*(chunk as *const u8).add(offset).cast()
+    }
+
+    #[inline]
+    unsafe fn fetch_row<'a>(offset: usize, chunk: *mut Chunk, _row: usize, _tick: u32) -> &'a T {
+        &*(chunk as *const u8).add(offset).cast()
+    }
+}
+
+/// Fetches `F` from archtypes which have it, and `None` from those which don't, without
+/// requiring it for a match.
+unsafe impl<F: Fetch> Fetch for Option<F> {
+    type Chunk<'a> = Option<F::Chunk<'a>>;
+    type Item<'a> = Option<F::Item<'a>>;
+    type State = Option<F::State>;
+
+    fn access(registry: &Registry, access: &mut Access) {
+        let required = access.required.clone();
+        let required_vectors = access.required_vectors.clone();
+        let required_shared = access.required_shared.clone();
+        let required_sparse = access.required_sparse.clone();
+        F::access(registry, access);
+        access.required = required;
+        access.required_vectors = required_vectors;
+        access.required_shared = required_shared;
+        access.required_sparse = required_sparse;
+    }
+
+    fn prepare(world: &World, archtype: &Archtype) -> Option<Self::State> {
+        Some(F::prepare(world, archtype))
+    }
+
+    #[inline]
+    unsafe fn fetch_chunk<'a>(state: Self::State, chunk: *mut Chunk, tick: u32) -> Self::Chunk<'a> {
+        state.map(|state| F::fetch_chunk(state, chunk, tick))
+    }
+
+    #[inline]
+    unsafe fn fetch_row<'a>(
+        state: Self::State,
+        chunk: *mut Chunk,
+        row: usize,
+        tick: u32,
+    ) -> Self::Item<'a> {
+        let state = state.filter(|&state| F::matches_row(state, chunk, row))?;
+        Some(F::fetch_row(state, chunk, row, tick))
+    }
+}
+
+macro_rules! impl_fetch_tuple {
+    ($($name:ident),*) => {
+        #[allow(non_snake_case, unused_variables, clippy::unused_unit)]
+        unsafe impl<$($name: Fetch),*> Fetch for ($($name,)*) {
+            type Chunk<'a> = ($($name::Chunk<'a>,)*);
+            type Item<'a> = ($($name::Item<'a>,)*);
+            type State = ($($name::State,)*);
+
+            fn access(registry: &Registry, access: &mut Access) {
+                $($name::access(registry, access);)*
+            }
+
+            fn prepare(world: &World, archtype: &Archtype) -> Option<Self::State> {
+                Some(($($name::prepare(world, archtype)?,)*))
+            }
+
+            #[inline]
+            unsafe fn matches_row(state: Self::State, chunk: *const Chunk, row: usize) -> bool {
+                let ($($name,)*) = state;
+                true $(&& $name::matches_row($name, chunk, row))*
+            }
+
+            #[inline]
+            unsafe fn fetch_chunk<'a>(
+                state: Self::State,
+                chunk: *mut Chunk,
+                tick: u32,
+            ) -> Self::Chunk<'a> {
+                let ($($name,)*) = state;
+                ($($name::fetch_chunk($name, chunk, tick),)*)
+            }
+
+            #[inline]
+            unsafe fn fetch_row<'a>(
+                state: Self::State,
+                chunk: *mut Chunk,
+                row: usize,
+                tick: u32,
+            ) -> Self::Item<'a> {
+                let ($($name,)*) = state;
+                ($($name::fetch_row($name, chunk, row, tick),)*)
+            }
+        }
+    };
+}
+
+impl_fetch_tuple!();
+impl_fetch_tuple!(A);
+impl_fetch_tuple!(A, B);
+impl_fetch_tuple!(A, B, C);
+impl_fetch_tuple!(A, B, C, D);
+impl_fetch_tuple!(A, B, C, D, E);
+impl_fetch_tuple!(A, B, C, D, E, F);
+impl_fetch_tuple!(A, B, C, D, E, F, G);
+impl_fetch_tuple!(A, B, C, D, E, F, G, H);
+
+/// Restricts the things visited by a query without fetching any data.
+pub trait Filter {
+    /// Per-archtype state used to evaluate the filter within that archtype's chunks.
+    type State: Copy;
+
+    fn access(registry: &Registry, access: &mut Access);
+
+    fn prepare(world: &World, archtype: &Archtype) -> Self::State;
+
+    /// # Safety
+    ///
+    /// `chunk` must belong to the archtype `state` was prepared for, and `row` must be less than
+    /// the chunk's length.
+    unsafe fn matches_row(state: Self::State, chunk: *const Chunk, row: usize, since: u32) -> bool;
+
+    /// Returns false if no row in the chunk can match, so the chunk can be skipped without
+    /// looking at its rows. May return true even if no row matches.
+    ///
+    /// # Safety
+    ///
+    /// `chunk` must belong to the archtype `state` was prepared for.
+    unsafe fn matches_chunk(state: Self::State, chunk: *const Chunk, since: u32) -> bool {
+        (0..(*chunk).len as usize).any(|row| Self::matches_row(state, chunk, row, since))
+    }
+}
+
+/// Matches things which have the part `T`, without borrowing it.
+pub struct With<T>(PhantomData<T>);
+
+/// Matches things which do not have the part `T`.
+pub struct Without<T>(PhantomData<T>);
+
+/// Matches things whose part `T` was written after the query's `changed_since` tick. Sparse parts
+/// have no change ticks, so building a query which filters on one panics.
+pub struct Changed<T>(PhantomData<T>);
+
+impl<T: Any> Filter for With<T> {
+    /// Set if `T` is sparse, and so has to be checked row by row.
+    type State = Option<SparseState<T>>;
+
+    fn access(registry: &Registry, access: &mut Access) {
+        require_part(registry, access, registered_part::<T>(registry));
+    }
+
+    fn prepare(world: &World, archtype: &Archtype) -> Self::State {
+        SparseState::prepare(world, archtype)
+    }
+
+    #[inline]
+    unsafe fn matches_row(
+        state: Self::State,
+        chunk: *const Chunk,
+        row: usize,
+        _since: u32,
+    ) -> bool {
+        state.is_none_or(|sparse| sparse.get(chunk, row).is_some())
+    }
+}
+
+impl<T: Any> Filter for Without<T> {
+    /// Set if `T` is sparse, and so has to be checked row by row.
+    type State = Option<SparseState<T>>;
+
+    fn access(registry: &Registry, access: &mut Access) {
+        let part = registered_part::<T>(registry);
+        if registry.part(part).is_sparse() {
+            access.sparse.set(part);
+        } else {
+            access.excluded.set(part);
+        }
+    }
+
+    fn prepare(world: &World, archtype: &Archtype) -> Self::State {
+        SparseState::prepare(world, archtype)
+    }
+
+    #[inline]
+    unsafe fn matches_row(
+        state: Self::State,
+        chunk: *const Chunk,
+        row: usize,
+        _since: u32,
+    ) -> bool {
+        state.is_none_or(|sparse| sparse.get(chunk, row).is_none())
+    }
+}
+
+impl<T: Any> Filter for Changed<T> {
+    type State = ColumnState;
+
+    fn access(registry: &Registry, access: &mut Access) {
+        let part = tracked_part::<T>(registry);
+        access.required.set(part);
+        // The change ticks of `T` are read, so the query must not run alongside writers of `T`.
+        access.add_filter_read(part);
+    }
+
+    fn prepare(world: &World, archtype: &Archtype) -> ColumnState {
+        ColumnState::prepare::<T>(world.registry, archtype).unwrap()
+    }
+
+    #[inline]
+    unsafe fn matches_row(state: ColumnState, chunk: *const Chunk, row: usize, since: u32) -> bool {
+        state.ticks(chunk).add(row).read() > since
+    }
+
+    #[inline]
+    unsafe fn matches_chunk(state: ColumnState, chunk: *const Chunk, since: u32) -> bool {
+        state.chunk_tick(chunk).read() > since
+    }
+}
+
+macro_rules! impl_filter_tuple {
+    ($($name:ident),*) => {
+        #[allow(non_snake_case, unused_variables, clippy::unused_unit)]
+        impl<$($name: Filter),*> Filter for ($($name,)*) {
+            type State = ($($name::State,)*);
+
+            fn access(registry: &Registry, access: &mut Access) {
+                $($name::access(registry, access);)*
+            }
+
+            fn prepare(world: &World, archtype: &Archtype) -> Self::State {
+                ($($name::prepare(world, archtype),)*)
+            }
+
+            #[inline]
+            unsafe fn matches_row(
+                state: Self::State,
+                chunk: *const Chunk,
+                row: usize,
+                since: u32,
+            ) -> bool {
+                let ($($name,)*) = state;
+                true $(&& $name::matches_row($name, chunk, row, since))*
+            }
+
+            #[inline]
+            unsafe fn matches_chunk(state: Self::State, chunk: *const Chunk, since: u32) -> bool {
+                let ($($name,)*) = state;
+                true $(&& $name::matches_chunk($name, chunk, since))*
+            }
+        }
+    };
+}
+
+impl_filter_tuple!();
+impl_filter_tuple!(A);
+impl_filter_tuple!(A, B);
+impl_filter_tuple!(A, B, C);
+impl_filter_tuple!(A, B, C, D);
+
+type QueryState<Q, F> = (<Q as Fetch>::State, <F as Filter>::State);
+
+/// A typed view over every thing in the world having the parts fetched by `Q` and passing the
+/// filter `F`.
+pub struct Query<'world, 'registry, Q: Fetch, F: Filter = ()> {
+    world: &'world World<'registry>,
+    archtypes: Vec<(usize, QueryState<Q, F>)>,
+    /// The flag of every column and sparse set the query borrows, and whether it borrows it
+    /// mutably.
+    borrows: Vec<(&'world BorrowFlag, bool)>,
+    /// Whether rows are matched by sparse parts, which rules out fetching whole chunks.
+    sparse: bool,
+    /// The thing slots holding every sparse part the query requires, if it requires any. Rows
+    /// are found through these slots rather than by visiting every chunk.
+    slots: Option<BitSet>,
+    tick: u32,
+    since: u32,
+}
+
+impl<'world, 'registry, Q: Fetch, F: Filter> Query<'world, 'registry, Q, F> {
+    pub(crate) fn new(world: &'world World<'registry>) -> Self {
+        let registry = world.registry;
+        let access = Self::access(registry);
+        let archtypes = world
+            .archtypes
+            .iter()
+            .enumerate()
+            .filter(|(_, archtype)| access.matches(archtype))
+            .filter_map(|(index, archtype)| {
+                let fetch = Q::prepare(world, archtype)?;
+                let filter = F::prepare(world, archtype);
+                Some((index, (fetch, filter)))
+            })
+            .collect::<Vec<_>>();
+
+        let mut query = Self {
+            world,
+            archtypes: Vec::new(),
+            borrows: Vec::new(),
+            sparse: !access.sparse.is_empty(),
+            slots: None,
+            tick: 0,
+            since: 0,
+        };
+        // Borrows taken before a conflict is found are released when `query` is dropped.
+        let accessed = || {
+            access
+                .reads
+                .iter_set_bits()
+                .map(|part| (part, false))
+                .chain(access.writes.iter_set_bits().map(|part| (part, true)))
+        };
+        for (part, exclusive) in accessed() {
+            if let Some(set) = world.sparse_set(part) {
+                query.borrow(&set.borrow, part, exclusive);
+            }
+        }
+        for &(index, _) in &archtypes {
+            let archtype = &world.archtypes[index];
+            for (part, exclusive) in accessed() {
+                let columns = archtype.column(part).into_iter();
+                for column in columns.chain(archtype.vector_column(part)) {
+                    query.borrow(&column.borrow, part, exclusive);
+                }
+            }
+        }
+        query.archtypes = archtypes;
+        query.slots = access
+            .required_sparse
+            .iter_set_bits()
+            .map(|part| world.sparse_set(part).unwrap().occupancy())
+            .fold(None, |slots: Option<BitSet>, occupancy| match slots {
+                Some(slots) => Some(slots.as_slice().and(occupancy)),
+                None => Some(occupancy.to_bit_set()),
+            });
+
+        // Everything written through this query is stamped with a single new tick.
+        query.tick = if access.writes.is_empty() {
+            world.tick()
+        } else {
+            world.next_tick()
+        };
+        query
+    }
+
+    fn borrow(&mut self, flag: &'world BorrowFlag, part: usize, exclusive: bool) {
+        let borrowed = if exclusive {
+            flag.try_borrow_mut()
+        } else {
+            flag.try_borrow()
+        };
+        if !borrowed {
+            let name = self.world.registry.part(part).name;
+            already_borrowed(name, flag.is_borrowed_mut());
+        }
+        self.borrows.push((flag, exclusive));
+    }
+
+    /// Returns the parts the query requires, excludes, reads and writes.
+    pub(crate) fn access(registry: &Registry) -> Access {
+        let mut access = Access::default();
+        Q::access(registry, &mut access);
+        F::access(registry, &mut access);
+        access
+    }
+
+    /// Sets the tick that `Changed` filters compare against, typically a value previously
+    /// returned by `World::tick`.
+    pub fn changed_since(mut self, tick: u32) -> Self {
+        self.since = tick;
+        self
+    }
+
+    /// Iterates over the column data of every non-empty chunk matched by the query. Row filters
+    /// select whole chunks, and are only checked at chunk granularity, so for instance `Changed`
+    /// yields every chunk whose column was written since the given tick, even if the rows written
+    /// have since been removed.
+    ///
+    /// Panics if the query fetches or filters by sparse parts, which aren't stored in chunks.
+    pub fn iter_chunks(&mut self) -> ChunkIter<'_, 'registry, Q, F> {
+        self.check_not_sparse();
+        let (tick, since) = (self.tick, self.since);
+        ChunkIter {
+            cursor: self.cursor(),
+            tick,
+            since,
+        }
+    }
+
+    /// Iterates over every row matched by the query. Queries requiring sparse parts visit rows
+    /// in the order of their things' slots, only looking at things which have every such part.
+    pub fn iter(&mut self) -> Iter<'_, 'registry, Q, F> {
+        let (tick, since) = (self.tick, self.since);
+        Iter {
+            cursor: self.cursor(),
+            slots: self.slots.as_ref().map(BitSet::iter),
+            current: None,
+            row: 0,
+            len: 0,
+            tick,
+            since,
+        }
+    }
+
+    /// Calls `f` with the column data of every non-empty chunk matched by the query, splitting
+    /// the chunks between the threads of `pool`. Chunks are selected as for `iter_chunks`.
+    ///
+    /// Each chunk is handed to exactly one call of `f`, and the query's access rules out fetching
+    /// a part mutably more than once, so no two workers can borrow the same column mutably.
+    ///
+    /// The chunk data must be `Send`, so parts read are `Sync` and parts written are `Send`.
+    ///
+    /// Panics if the query fetches or filters by sparse parts, as `iter_chunks` does.
+    pub fn par_for_each_chunk(&mut self, pool: &ThreadPool, f: impl Fn(Q::Chunk<'_>) + Sync)
+    where
+        for<'c> Q::Chunk<'c>: Send,
+    {
+        self.check_not_sparse();
+        let (tick, since) = (self.tick, self.since);
+        let mut cursor = self.cursor();
+        let mut chunks = Vec::new();
+        while let Some((chunk, (fetch, filter))) = cursor.next() {
+            if unsafe { F::matches_chunk(filter, chunk, since) } {
+                chunks.push(SendChunk(chunk, fetch));
+            }
+        }
+
+        // A few batches per thread evens out the load when chunks take different amounts of work.
+        let batch = chunks.len().div_ceil(pool.threads() * 4).max(1);
+        let f = &f;
+        pool.scope(|scope| {
+            for chunks in chunks.chunks(batch) {
+                scope.spawn(move || {
+                    for &SendChunk(chunk, fetch) in chunks {
+                        // SAFETY: every chunk appears in exactly one batch, and the query is held
+                        // mutably until all batches are done.
+                        f(unsafe { Q::fetch_chunk(fetch, chunk, tick) });
+                    }
+                });
+            }
+        });
+    }
+
+    #[inline]
+    fn check_not_sparse(&self) {
+        if self.sparse {
+            sparse_chunks()
+        }
+    }
+
+    fn cursor(&self) -> ChunkCursor<'_, 'registry, QueryState<Q, F>> {
+        ChunkCursor {
+            // The chunks live in their own mapping, so writing through this pointer doesn't
+            // alias the shared reference to the world. The query's column borrows stop anything
+            // else touching the columns it writes.
+            chunks: self.world.chunks.as_ptr() as *mut Chunk,
+            world: self.world,
+            archtypes: &self.archtypes,
+            archtype: 0,
+            chunk: 0,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<'world, 'registry, Q: Fetch, F: Filter> Drop for Query<'world, 'registry, Q, F> {
+    fn drop(&mut self) {
+        for &(flag, exclusive) in &self.borrows {
+            if exclusive {
+                flag.release_mut();
+            } else {
+                flag.release();
+            }
+        }
+    }
+}
+
+#[cold]
+#[inline(never)]
+fn already_borrowed(part: &str, mutably: bool) {
+    if mutably {
+        panic!("part `{}` is already borrowed mutably by a query", part);
+    } else {
+        panic!(
+            "part `{}` is already borrowed by a query or `World::get`",
+            part
+        );
+    }
+}
+
+#[cold]
+#[inline(never)]
+fn sparse_chunks() {
+    panic!("sparse parts can only be iterated a row at a time");
+}
+
+/// A chunk and the state needed to fetch from it, handed to a worker thread.
+#[derive(Copy, Clone)]
+struct SendChunk<S>(*mut Chunk, S);
+
+// SAFETY: `par_for_each_chunk` requires the data fetched from a chunk to be `Send`, and a chunk is
+// only handed to one thread at a time. The fetch state is only offsets into the chunk.
+unsafe impl<S> Send for SendChunk<S> {}
+unsafe impl<S> Sync for SendChunk<S> {}
+
+/// Walks the non-empty chunks of a query's matching archtypes.
+struct ChunkCursor<'query, 'registry, S> {
+    world: &'query World<'registry>,
+    chunks: *mut Chunk,
+    archtypes: &'query [(usize, S)],
+    archtype: usize,
+    chunk: usize,
+    _marker: PhantomData<&'query mut Chunk>,
+}
+
+impl<'query, 'registry, S: Copy> ChunkCursor<'query, 'registry, S> {
+    fn next(&mut self) -> Option<(*mut Chunk, S)> {
+        while let Some(&(archtype, state)) = self.archtypes.get(self.archtype) {
+            let chunks = &self.world.archtypes[archtype].chunks;
+            match chunks.get(self.chunk) {
+                Some(&chunk) => {
+                    self.chunk += 1;
+                    // SAFETY: each chunk is visited at most once, and the query holds its columns
+                    // exclusively for the lifetime of the cursor.
+                    unsafe {
+                        let chunk = self.chunks.add(self.world.chunk_index(chunk));
+                        if (*chunk).len != 0 {
+                            return Some((chunk, state));
+                        }
+                    }
+                }
+                None => {
+                    self.archtype += 1;
+                    self.chunk = 0;
+                }
+            }
+        }
+        None
+    }
+
+    /// Finds the chunk and row of the thing in `slot`, if it's in a matched archtype.
+    ///
+    /// # Safety
+    ///
+    /// The caller must not locate the same slot more than once.
+    unsafe fn locate(&self, slot: usize) -> Option<(*mut Chunk, usize, S)> {
+        let (archtype, chunk, row) = self.world.locate_slot(slot)?;
+        let index = self
+            .archtypes
+            .binary_search_by_key(&archtype, |&(archtype, _)| archtype)
+            .ok()?;
+        let chunk = self.chunks.add(self.world.chunk_index(chunk));
+        Some((chunk, row, self.archtypes[index].1))
+    }
+}
+
+pub struct ChunkIter<'query, 'registry, Q: Fetch, F: Filter> {
+    cursor: ChunkCursor<'query, 'registry, QueryState<Q, F>>,
+    tick: u32,
+    since: u32,
+}
+
+impl<'query, 'registry, Q: Fetch, F: Filter> Iterator for ChunkIter<'query, 'registry, Q, F> {
+    type Item = Q::Chunk<'query>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((chunk, (fetch, filter))) = self.cursor.next() {
+            unsafe {
+                if F::matches_chunk(filter, chunk, self.since) {
+                    return Some(Q::fetch_chunk(fetch, chunk, self.tick));
+                }
+            }
+        }
+        None
+    }
+}
+
+pub struct Iter<'query, 'registry, Q: Fetch, F: Filter> {
+    cursor: ChunkCursor<'query, 'registry, QueryState<Q, F>>,
+    /// Set if rows are found through the slots holding the query's sparse parts.
+    slots: Option<bit_set::Iter<'query>>,
+    current: Option<(*mut Chunk, QueryState<Q, F>)>,
+    row: usize,
+    len: usize,
+    tick: u32,
+    since: u32,
+}
+
+impl<'query, 'registry, Q: Fetch, F: Filter> Iterator for Iter<'query, 'registry, Q, F> {
+    type Item = Q::Item<'query>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(slots) = &mut self.slots {
+            for slot in slots {
+                // SAFETY: each slot is yielded once, and holds a different thing.
+                unsafe {
+                    let (chunk, row, (fetch, filter)) = match self.cursor.locate(slot) {
+                        Some(location) => location,
+                        None => continue,
+                    };
+                    if Q::matches_row(fetch, chunk, row)
+                        && F::matches_row(filter, chunk, row, self.since)
+                    {
+                        return Some(Q::fetch_row(fetch, chunk, row, self.tick));
+                    }
+                }
+            }
+            return None;
+        }
+
+        loop {
+            if let Some((chunk, (fetch, filter))) = self.current {
+                while self.row < self.len {
+                    let row = self.row;
+                    self.row += 1;
+                    // SAFETY: rows are yielded at most once, and `len` was read from the chunk.
+                    unsafe {
+                        if Q::matches_row(fetch, chunk, row)
+                            && F::matches_row(filter, chunk, row, self.since)
+                        {
+                            return Some(Q::fetch_row(fetch, chunk, row, self.tick));
+                        }
+                    }
+                }
+            }
+
+            let (chunk, state) = self.cursor.next()?;
+            self.current = Some((chunk, state));
+            self.row = 0;
+            // Skip the rows of chunks the filter rules out as a whole.
+            self.len = unsafe {
+                if F::matches_chunk(state.1, chunk, self.since) {
+                    (*chunk).len as usize
+                } else {
+                    0
+                }
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Copy, Clone, PartialEq, Debug)]
+    struct PosX(f32);
+
+    #[derive(Copy, Clone, PartialEq, Debug)]
+    struct PosY(f32);
+
+    #[derive(Copy, Clone, PartialEq, Debug)]
+    struct Speed(f32);
+
+    unsafe impl Blit for PosX {}
+    unsafe impl Blit for PosY {}
+    unsafe impl Blit for Speed {}
+
+    #[test]
+    fn iter_chunks() {
+        let mut registry = Registry::new();
+        registry.register_part::<PosX>();
+        registry.register_part::<PosY>();
+        registry.register_part::<Speed>();
+        let mut world = World::new(&registry);
+
+        let mut factory = world.factory();
+        let things = (0..5000)
+            .map(|i| {
+                let thing = factory.thing().add_part(PosX(i as f32)).add_part(PosY(0.0));
+                if i % 2 == 0 {
+                    thing.add_part(Speed(2.0)).finish()
+                } else {
+                    thing.finish()
+                }
+            })
+            .collect::<Vec<_>>();
+        let _unmatched = factory.thing().add_part(PosY(0.0)).finish();
+
+        let mut count = 0;
+        let mut query = world.query::<(&PosX, &mut PosY)>();
+        for (xs, ys) in query.iter_chunks() {
+            assert_eq!(xs.len(), ys.len());
+            for (x, y) in xs.iter().zip(ys) {
+                y.0 = x.0 * 2.0;
+            }
+            count += xs.len();
+        }
+        assert_eq!(count, 5000);
+        // Release the query's mutable borrow of `PosY`.
+        drop(query);
+
+        let mut count = 0;
+        let mut query = world.query::<(&Speed, &PosY)>();
+        for (speeds, ys) in query.iter_chunks() {
+            for (speed, y) in speeds.iter().zip(ys) {
+                assert_eq!(speed.0, 2.0);
+                assert_eq!(y.0 % 4.0, 0.0);
+            }
+            count += speeds.len();
+        }
+        assert_eq!(count, 2500);
+
+        for (i, &thing) in things.iter().enumerate() {
+            assert_eq!(
+                world.get::<PosY>(thing).as_deref(),
+                Some(&PosY(i as f32 * 2.0))
+            );
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "PosX` mutably more than once")]
+    fn conflicting_access() {
+        let mut registry = Registry::new();
+        registry.register_part::<PosX>();
+        let world = World::new(&registry);
+        world.query::<(&PosX, &mut PosX)>();
+    }
+
+    #[test]
+    fn changed_access() {
+        let mut registry = Registry::new();
+        registry.register_part::<PosX>();
+        let part = registry.part_index::<PosX>().unwrap();
+
+        let mut access = Access::default();
+        <&PosX as Fetch>::access(&registry, &mut access);
+        <Changed<PosX> as Filter>::access(&registry, &mut access);
+        assert!(access.reads.contains(part));
+        assert!(!access.writes.contains(part));
+
+        let mut access = Access::default();
+        <&mut PosX as Fetch>::access(&registry, &mut access);
+        <Changed<PosX> as Filter>::access(&registry, &mut access);
+        assert!(!access.reads.contains(part));
+        assert!(access.writes.contains(part));
+    }
+
+    #[test]
+    fn filters() {
+        let mut registry = Registry::new();
+        registry.register_part::<PosX>();
+        registry.register_part::<PosY>();
+        registry.register_part::<Speed>();
+        let mut world = World::new(&registry);
+
+        let mut factory = world.factory();
+        let things = (0..100)
+            .map(|i| {
+                let thing = factory.thing().add_part(PosX(i as f32));
+                if i % 4 == 0 {
+                    thing.add_part(Speed(1.0)).finish()
+                } else {
+                    thing.finish()
+                }
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            world.query_filtered::<&PosX, With<Speed>>().iter().count(),
+            25
+        );
+        assert_eq!(
+            world
+                .query_filtered::<&PosX, Without<Speed>>()
+                .iter()
+                .count(),
+            75
+        );
+        assert_eq!(
+            world
+                .query_filtered::<&PosX, (With<Speed>, Without<Speed>)>()
+                .iter()
+                .count(),
+            0
+        );
+
+        let mut with_speed = 0;
+        for (x, speed) in world.query::<(&PosX, Option<&mut Speed>)>().iter() {
+            if let Some(speed) = speed {
+                assert_eq!(x.0 % 4.0, 0.0);
+                speed.0 = x.0;
+                with_speed += 1;
+            }
+        }
+        assert_eq!(with_speed, 25);
+        assert_eq!(world.get::<Speed>(things[8]).as_deref(), Some(&Speed(8.0)));
+
+        let tick = world.tick();
+        for &thing in things.iter().step_by(10) {
+            world.get_mut::<PosX>(thing).unwrap().0 += 1000.0;
+        }
+        world.add_part(things[1], PosY(0.0));
+
+        let mut changed = world
+            .query_filtered::<&PosX, Changed<PosX>>()
+            .changed_since(tick)
+            .iter()
+            .map(|x| x.0)
+            .collect::<Vec<_>>();
+        changed.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(
+            changed,
+            (0..100)
+                .step_by(10)
+                .map(|i| i as f32 + 1000.0)
+                .collect::<Vec<_>>()
+        );
+
+        let tick = world.tick();
+        for x in world.query::<&mut PosX>().iter().filter(|x| x.0 > 1000.0) {
+            x.0 -= 1000.0;
+        }
+        let changed = world
+            .query_filtered::<&PosX, Changed<PosX>>()
+            .changed_since(tick)
+            .iter()
+            .count();
+        assert_eq!(changed, 100);
+        let changed = world
+            .query_filtered::<&PosY, Changed<PosY>>()
+            .changed_since(tick)
+            .iter()
+            .count();
+        assert_eq!(changed, 0);
+    }
+
+    #[test]
+    fn vector_parts() {
+        let mut registry = Registry::new();
+        registry.register_part::<PosX>();
+        registry.register_part::<Speed>();
+        let mut world = World::new(&registry);
+
+        let mut factory = world.factory();
+        for i in 0..100 {
+            let thing = factory.thing().add_part(PosX(0.0));
+            if i % 4 == 0 {
+                thing.finish();
+            } else {
+                thing.add_vector_part(vec![Speed(1.0); i % 4]).finish();
+            }
+        }
+
+        for (x, speeds) in world.query::<(&mut PosX, &mut [Speed])>().iter() {
+            for speed in speeds.iter_mut() {
+                speed.0 *= 2.0;
+            }
+            x.0 = speeds.iter().map(|speed| speed.0).sum();
+        }
+
+        let mut count = 0;
+        for (xs, speeds) in world.query::<(&PosX, &[Speed])>().iter_chunks() {
+            assert_eq!(xs.len(), speeds.len());
+            for (x, speeds) in xs.iter().zip(speeds.iter()) {
+                assert!(speeds.iter().all(|speed| speed.0 == 2.0));
+                assert_eq!(x.0, speeds.len() as f32 * 2.0);
+            }
+            count += xs.len();
+        }
+        assert_eq!(count, 75);
+        assert_eq!(world.query::<&Speed>().iter().count(), 0);
+    }
+
+    #[test]
+    fn shared_parts() {
+        let mut registry = Registry::new();
+        registry.register_part::<PosX>();
+        registry.register_part::<Speed>();
+        let mut world = World::new(&registry);
+
+        let mut factory = world.factory();
+        for i in 0..3000 {
+            let thing = factory.thing().add_part(PosX(0.0));
+            if i % 3 == 0 {
+                thing.finish();
+            } else {
+                thing.add_shared_part(Speed((i % 3) as f32)).finish();
+            }
+        }
+
+        for (speed, xs) in world.query::<(Shared<Speed>, &mut PosX)>().iter_chunks() {
+            for x in xs {
+                x.0 += speed.0;
+            }
+        }
+
+        let mut sum = 0.0;
+        for (x, speed) in world.query::<(&PosX, Shared<Speed>)>().iter() {
+            assert_eq!(x.0, speed.0);
+            sum += x.0;
+        }
+        assert_eq!(sum, 3000.0);
+        assert_eq!(world.query::<&Speed>().iter().count(), 0);
+    }
+
+    #[test]
+    fn changed_chunks() {
+        let mut registry = Registry::new();
+        registry.register_part::<PosX>();
+        registry.register_part::<PosY>();
+        let mut world = World::new(&registry);
+
+        let mut factory = world.factory();
+        let things = (0..10_000)
+            .map(|i| {
+                factory
+                    .thing()
+                    .add_part(PosX(i as f32))
+                    .add_part(PosY(0.0))
+                    .finish()
+            })
+            .collect::<Vec<_>>();
+        let chunks = world.query::<&PosX>().iter_chunks().count();
+        assert!(chunks > 2);
+
+        let tick = world.tick();
+        assert_eq!(
+            world
+                .query_filtered::<&PosX, Changed<PosX>>()
+                .changed_since(tick)
+                .iter_chunks()
+                .count(),
+            0
+        );
+
+        // Writing one row only marks its own chunk and column.
+        world.get_mut::<PosX>(things[5000]).unwrap().0 = -1.0;
+        let mut query = world
+            .query_filtered::<&PosX, Changed<PosX>>()
+            .changed_since(tick);
+        let changed = query.iter_chunks().collect::<Vec<_>>();
+        assert_eq!(changed.len(), 1);
+        assert!(changed[0].contains(&PosX(-1.0)));
+        assert_eq!(
+            world
+                .query_filtered::<&PosY, Changed<PosY>>()
+                .changed_since(tick)
+                .iter_chunks()
+                .count(),
+            0
+        );
+
+        // Mutable chunk borrows mark the whole column.
+        let tick = world.tick();
+        for ys in world.query::<&mut PosY>().iter_chunks().take(2) {
+            ys[0].0 = 1.0;
+        }
+        assert_eq!(
+            world
+                .query_filtered::<&PosY, (Changed<PosY>, With<PosX>)>()
+                .changed_since(tick)
+                .iter_chunks()
+                .count(),
+            2
+        );
+        assert_eq!(
+            world
+                .query_filtered::<&PosY, Changed<PosY>>()
+                .changed_since(tick)
+                .iter()
+                .count(),
+            2 * world.archtypes[0].layout.capacity as usize
+        );
+    }
+
+    #[test]
+    fn par_for_each_chunk() {
+        let mut registry = Registry::new();
+        registry.register_part::<PosX>();
+        registry.register_part::<PosY>();
+        registry.register_part::<Speed>();
+        let mut world = World::new(&registry);
+
+        let mut factory = world.factory();
+        let things = (0..20000)
+            .map(|i| {
+                let thing = factory.thing().add_part(PosX(i as f32)).add_part(PosY(0.0));
+                if i % 3 == 0 {
+                    thing.add_part(Speed(1.0)).finish()
+                } else {
+                    thing.finish()
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let pool = ThreadPool::new(4);
+        let count = std::sync::atomic::AtomicUsize::new(0);
+        world
+            .query::<(&PosX, &mut PosY)>()
+            .par_for_each_chunk(&pool, |(xs, ys)| {
+                for (x, y) in xs.iter().zip(ys) {
+                    y.0 = x.0 * 2.0;
+                }
+                count.fetch_add(xs.len(), std::sync::atomic::Ordering::Relaxed);
+            });
+        assert_eq!(count.into_inner(), 20000);
+        for (i, &thing) in things.iter().enumerate() {
+            assert_eq!(
+                world.get::<PosY>(thing).as_deref(),
+                Some(&PosY(i as f32 * 2.0))
+            );
+        }
+
+        // Chunk filters apply as they do for `iter_chunks`.
+        let tick = world.tick();
+        world
+            .query_filtered::<&mut PosY, With<Speed>>()
+            .par_for_each_chunk(&pool, |ys| ys.iter_mut().for_each(|y| y.0 = -1.0));
+        let mut query = world
+            .query_filtered::<&PosY, Changed<PosY>>()
+            .changed_since(tick);
+        let chunks = std::sync::Mutex::new(0);
+        query.par_for_each_chunk(&pool, |ys| {
+            assert!(ys.iter().all(|y| y.0 == -1.0));
+            *chunks.lock().unwrap() += 1;
+        });
+        assert_eq!(
+            chunks.into_inner().unwrap(),
+            world
+                .query_filtered::<&PosY, With<Speed>>()
+                .iter_chunks()
+                .count()
+        );
+    }
+
+    fn panic_message(f: impl FnOnce()) -> String {
+        let panic = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).unwrap_err();
+        match panic.downcast::<String>() {
+            Ok(message) => *message,
+            Err(panic) => panic.downcast::<&str>().unwrap().to_string(),
+        }
+    }
+
+    #[test]
+    fn borrow_tracking() {
+        let mut registry = Registry::new();
+        registry.register_part::<PosX>();
+        registry.register_part::<PosY>();
+        registry.register_part::<Speed>();
+        let mut world = World::new(&registry);
+
+        let mut factory = world.factory();
+        let moving = factory
+            .thing()
+            .add_part(PosX(0.0))
+            .add_part(Speed(1.0))
+            .finish();
+        let fixed = factory.thing().add_part(PosX(0.0)).finish();
+
+        // Shared borrows of a part, and mutable borrows of disjoint parts or archtypes, coexist.
+        {
+            let mut xs = world.query::<&PosX>();
+            let _also_xs = world.query::<(ThingId, &PosX)>();
+            let mut speeds = world.query::<&mut Speed>();
+            for (x, speed) in xs.iter().zip(speeds.iter()) {
+                speed.0 += x.0;
+            }
+        }
+        {
+            let mut moving_xs = world.query_filtered::<&mut PosX, With<Speed>>();
+            let mut fixed_xs = world.query_filtered::<&PosX, Without<Speed>>();
+            for x in moving_xs.iter() {
+                x.0 = fixed_xs.iter().next().unwrap().0 + 1.0;
+            }
+            assert_eq!(world.get::<PosX>(fixed).as_deref(), Some(&PosX(0.0)));
+        }
+        assert_eq!(world.get::<PosX>(moving).as_deref(), Some(&PosX(1.0)));
+
+        let message = panic_message(|| {
+            let _xs = world.query::<(&PosX, &mut Speed)>();
+            world.query::<&Speed>();
+        });
+        assert!(
+            message.contains("Speed` is already borrowed mutably"),
+            "{}",
+            message
+        );
+
+        let message = panic_message(|| {
+            let _xs = world.query::<&PosX>();
+            world.query::<&mut PosX>();
+        });
+        assert!(
+            message.contains("PosX` is already borrowed by"),
+            "{}",
+            message
+        );
+
+        let message = panic_message(|| {
+            let _speeds = world.query::<&mut Speed>();
+            world.get::<Speed>(moving);
+        });
+        assert!(
+            message.contains("Speed` is already borrowed mutably"),
+            "{}",
+            message
+        );
+
+        // Parts returned by `get` stay borrowed until dropped, so no query can write them meanwhile.
+        let message = panic_message(|| {
+            let _speed = world.get::<Speed>(moving);
+            world.query::<&mut Speed>();
+        });
+        assert!(
+            message.contains("Speed` is already borrowed by a query or `World::get`"),
+            "{}",
+            message
+        );
+        let speed = world.get::<Speed>(moving).unwrap();
+        world.query::<&Speed>();
+        drop(speed);
+        world.query::<&mut Speed>();
+
+        // Filtering on changes reads the part's change ticks, so it conflicts with writers too.
+        let message = panic_message(|| {
+            let _speeds = world.query::<&mut Speed>();
+            world.query_filtered::<&PosX, Changed<Speed>>();
+        });
+        assert!(
+            message.contains("Speed` is already borrowed mutably"),
+            "{}",
+            message
+        );
+
+        // Failed queries release whatever they borrowed before the conflict.
+        let _ = panic_message(|| {
+            let _ys = world.query_filtered::<&PosX, Without<Speed>>();
+            world.query::<&mut PosX>();
+        });
+        world.query::<(&mut PosX, &mut Speed)>();
+    }
+
+    #[test]
+    fn tags() {
+        #[derive(Copy, Clone)]
+        struct Player;
+
+        unsafe impl Blit for Player {}
+
+        let mut registry = Registry::new();
+        registry.register_part::<PosX>();
+        registry.register_part::<Player>();
+        let mut world = World::new(&registry);
+
+        let mut factory = world.factory();
+        for i in 0..100 {
+            let thing = factory.thing().add_part(PosX(i as f32));
+            if i % 10 == 0 {
+                thing.add_part(Player).finish();
+            } else {
+                thing.finish();
+            }
+        }
+
+        let players = world
+            .query_filtered::<&PosX, With<Player>>()
+            .iter()
+            .map(|x| x.0 as usize)
+            .collect::<Vec<_>>();
+        assert_eq!(players, (0..100).step_by(10).collect::<Vec<_>>());
+        assert_eq!(
+            world
+                .query_filtered::<&PosX, Without<Player>>()
+                .iter()
+                .count(),
+            90
+        );
+
+        let message = panic_message(|| {
+            world.query::<&Player>();
+        });
+        assert!(
+            message.contains("Player` has no data to fetch"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn sparse_parts() {
+        #[derive(Copy, Clone, PartialEq, Debug)]
+        struct Target(u32);
+
+        #[derive(Copy, Clone)]
+        struct Selected;
+
+        unsafe impl Blit for Target {}
+        unsafe impl Blit for Selected {}
+
+        let mut registry = Registry::new();
+        registry.register_part::<PosX>();
+        registry.register_part::<Speed>();
+        registry.register_sparse_part::<Target>();
+        registry.register_sparse_part::<Selected>();
+        let mut world = World::new(&registry);
+
+        let mut factory = world.factory();
+        let things = (0..300)
+            .map(|i| {
+                let thing = factory.thing().add_part(PosX(i as f32));
+                let thing = if i % 2 == 0 {
+                    thing.add_part(Speed(1.0))
+                } else {
+                    thing
+                };
+                if i % 3 == 0 {
+                    thing.add_part(Target(i)).finish()
+                } else {
+                    thing.finish()
+                }
+            })
+            .collect::<Vec<_>>();
+        for &thing in things.iter().step_by(5) {
+            world.add_part(thing, Selected);
+        }
+
+        // Sparse parts are joined row by row with the chunk parts, across archtypes.
+        let mut query = world.query::<(&PosX, &mut Target)>();
+        for (x, target) in query.iter() {
+            assert_eq!(x.0 as u32, target.0);
+            target.0 += 1000;
+        }
+        assert_eq!(query.iter().count(), 100);
+        drop(query);
+        assert_eq!(
+            world.get::<Target>(things[3]).as_deref(),
+            Some(&Target(1003))
+        );
+
+        let mut query = world.query_filtered::<(&PosX, Option<&Target>), With<Speed>>();
+        let (targeted, untargeted): (Vec<_>, Vec<_>) =
+            query.iter().partition(|(_, target)| target.is_some());
+        assert_eq!((targeted.len(), untargeted.len()), (50, 100));
+        drop(query);
+
+        let mut selected = world
+            .query_filtered::<&PosX, (With<Selected>, Without<Target>)>()
+            .iter()
+            .map(|x| x.0 as usize)
+            .collect::<Vec<_>>();
+        selected.sort_unstable();
+        let expected = (0..300).step_by(5).filter(|i| i % 3 != 0);
+        assert_eq!(selected, expected.collect::<Vec<_>>());
+
+        // Queries requiring sparse parts only visit things holding all of them, but still only
+        // match things in matched archtypes.
+        let both = world
+            .query_filtered::<(&PosX, &Target), With<Selected>>()
+            .iter()
+            .map(|(x, _)| x.0 as usize)
+            .collect::<Vec<_>>();
+        assert_eq!(both, (0..300).step_by(15).collect::<Vec<_>>());
+        let moving = world
+            .query_filtered::<&Target, With<Speed>>()
+            .iter()
+            .count();
+        assert_eq!(moving, 50);
+        let still = world
+            .query_filtered::<&Target, Without<Speed>>()
+            .iter()
+            .count();
+        assert_eq!(still, 50);
+
+        // Sparse sets are borrowed like columns.
+        let message = panic_message(|| {
+            let _targets = world.query::<&Target>();
+            world.query::<&mut Target>();
+        });
+        assert!(
+            message.contains("Target` is already borrowed"),
+            "{}",
+            message
+        );
+
+        // Sparse parts aren't stored in chunks.
+        let message = panic_message(|| {
+            world.query::<&Target>().iter_chunks();
+        });
+        assert_eq!(message, "sparse parts can only be iterated a row at a time");
+
+        // Sparse parts have no change ticks to filter on, so the query is rejected up front.
+        let message = panic_message(|| {
+            world.query_filtered::<&PosX, Changed<Target>>();
+        });
+        assert!(
+            message.starts_with("`Changed` can't filter on sparse part `")
+                && message.ends_with("Target`, which has no change ticks"),
+            "{}",
+            message
+        );
+    }
+}
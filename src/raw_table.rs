@@ -1,8 +1,15 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+
 use crate::ring_buf::RingBuf;
 
+/// Maps generational handles to `u32` values. Handles which have been allocated, or reserved,
+/// but not yet `set` are unset, and have no value.
 pub struct RawTable<const N: usize> {
     storage: Box<[u32]>,
     free_ids: RingBuf<u32, N>,
+    /// Number of handles at the front of `free_ids` handed out by `reserve_handle`, which must be
+    /// removed before the free list is next modified.
+    reserved: AtomicU32,
 }
 
 impl<const N: usize> RawTable<N> {
@@ -27,6 +34,13 @@ impl<const N: usize> RawTable<N> {
         (!handle) & Self::GENERATION_MASK
     }
 
+    /// Returns the index of the table entry `handle` refers to, which stays the same for as long
+    /// as the handle is live.
+    #[inline(always)]
+    pub fn handle_index(handle: u32) -> usize {
+        Self::unpack_value(handle) as usize
+    }
+
     pub fn new() -> Self {
         // We want to reserve the largest value (all index bits set) for an invalid handle, so
         // reduce actual capacity by one.
@@ -38,10 +52,15 @@ impl<const N: usize> RawTable<N> {
         let len = (N - 1) as u32;
         let storage = (0..len).map(|_| 0).collect::<Box<[_]>>();
         let free_ids = (0..len).collect::<RingBuf<_, N>>();
-        Self { storage, free_ids }
+        Self {
+            storage,
+            free_ids,
+            reserved: AtomicU32::new(0),
+        }
     }
 
     pub fn allocate_handle(&mut self) -> u32 {
+        self.flush_reserved();
         let index = self.free_ids.pop_front().expect("id pool exhausted");
         let store = *self
             .storage
@@ -50,18 +69,77 @@ impl<const N: usize> RawTable<N> {
         Self::pack(index, Self::unpack_generation(store))
     }
 
+    /// Allocates a handle through a shared reference, so handles can be reserved from many
+    /// threads at once without locking.
+    pub fn reserve_handle(&self) -> u32 {
+        let reserved = self.reserved.fetch_add(1, Ordering::Relaxed);
+        let index = *self
+            .free_ids
+            .get(reserved as usize)
+            .expect("id pool exhausted");
+        let store = *self
+            .storage
+            .get(index as usize)
+            .expect("invalid entry in id free list");
+        Self::pack(index, Self::unpack_generation(store))
+    }
+
+    /// Removes handles given out by `reserve_handle` from the free list.
+    fn flush_reserved(&mut self) {
+        let reserved = std::mem::take(self.reserved.get_mut());
+        for _ in 0..reserved {
+            self.free_ids.pop_front();
+        }
+    }
+
     pub fn release_handle(&mut self, handle: u32) {
+        self.flush_reserved();
         let index = Self::unpack_value(handle);
         self.free_ids.push_back(index)
     }
 
+    /// Returns a handle which was allocated or reserved, but never set, to the free list. Returns
+    /// false, leaving the table alone, if `handle` is stale or has been set.
+    pub fn cancel_handle(&mut self, handle: u32) -> bool {
+        if !self.is_unset(handle) {
+            return false;
+        }
+        self.invalidate(handle);
+        self.release_handle(handle);
+        true
+    }
+
     pub fn get(&self, handle: u32) -> Option<u32> {
-        if let Some(&store) = self.storage.get(Self::unpack_value(handle) as usize) {
-            if Self::unpack_generation(store) == Self::unpack_generation(handle) {
-                return Some(Self::unpack_value(store));
-            }
+        self.get_store(handle).map(Self::unpack_value)
+    }
+
+    /// Like `get`, but also returns `None` if `handle` has been allocated or reserved but not
+    /// yet `set`.
+    pub fn get_if_set(&self, handle: u32) -> Option<u32> {
+        let value = self.get(handle)?;
+        (value != Self::INDEX_MASK).then_some(value)
+    }
+
+    /// Returns the value of the entry at `index`, whatever its generation, if it's set.
+    pub fn get_by_index(&self, index: usize) -> Option<u32> {
+        let value = Self::unpack_value(*self.storage.get(index)?);
+        (value != Self::INDEX_MASK).then_some(value)
+    }
+
+    /// Returns true if `handle` has been allocated or reserved, but not yet set.
+    pub fn is_unset(&self, handle: u32) -> bool {
+        self.get_store(handle)
+            .is_some_and(|store| Self::unpack_value(store) == Self::INDEX_MASK)
+    }
+
+    #[inline]
+    fn get_store(&self, handle: u32) -> Option<u32> {
+        let &store = self.storage.get(Self::unpack_value(handle) as usize)?;
+        if Self::unpack_generation(store) == Self::unpack_generation(handle) {
+            Some(store)
+        } else {
+            None
         }
-        None
     }
 
     pub fn set(&mut self, handle: u32, new_value: u32) -> bool {
@@ -76,14 +154,23 @@ impl<const N: usize> RawTable<N> {
         false
     }
 
+    /// Bumps the generation of `handle`'s slot, so it and any copies become stale, and clears
+    /// its value so the next handle for the slot starts unset.
     pub fn invalidate(&mut self, handle: u32) {
         let index = Self::unpack_value(handle) as usize;
         if let Some(store) = self.storage.get_mut(index) {
-            *store = store.wrapping_add(N as u32)
+            let generation = Self::unpack_generation(*store).wrapping_add(N as u32);
+            *store = Self::pack(Self::INDEX_MASK, generation)
         }
     }
 }
 
+impl<const N: usize> Default for RawTable<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -111,4 +198,97 @@ mod tests {
         table.invalidate(valid_handle);
         assert!(table.get(valid_handle).is_none());
     }
+
+    #[test]
+    fn unset_handles() {
+        let mut table = RawTable::<TABLE_SIZE>::new();
+        let allocated = table.allocate_handle();
+        let reserved = table.reserve_handle();
+        for handle in [allocated, reserved] {
+            assert!(table.is_unset(handle));
+            assert!(table.get(handle).is_some());
+            assert_eq!(table.get_if_set(handle), None);
+        }
+
+        table.set(allocated, 0);
+        assert!(!table.is_unset(allocated));
+        assert_eq!(table.get_if_set(allocated), Some(0));
+
+        // An invalidated slot starts unset again when its handle is next allocated.
+        table.invalidate(allocated);
+        table.release_handle(allocated);
+        assert_eq!(table.get_if_set(allocated), None);
+        let handles = (0..TABLE_SIZE - 2)
+            .map(|_| table.allocate_handle())
+            .collect::<Vec<_>>();
+        let reused = *handles.last().unwrap();
+        assert_eq!(
+            RawTable::<TABLE_SIZE>::handle_index(reused),
+            RawTable::<TABLE_SIZE>::handle_index(allocated)
+        );
+        assert!(table.is_unset(reused));
+        assert_eq!(table.get_if_set(reused), None);
+    }
+
+    #[test]
+    fn reserve() {
+        let mut table = RawTable::<TABLE_SIZE>::new();
+        let allocated = table.allocate_handle();
+        table.set(allocated, 1);
+
+        let reserved = std::thread::scope(|scope| {
+            let table = &table;
+            let threads = (0..4)
+                .map(|_| {
+                    scope
+                        .spawn(move || (0..100).map(|_| table.reserve_handle()).collect::<Vec<_>>())
+                })
+                .collect::<Vec<_>>();
+            threads
+                .into_iter()
+                .flat_map(|thread| thread.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+
+        let mut unique = reserved.clone();
+        unique.sort_unstable();
+        unique.dedup();
+        assert_eq!(unique.len(), 400);
+        assert!(!unique.contains(&allocated));
+        assert!(reserved.iter().all(|&handle| table.is_unset(handle)));
+
+        // Reserved handles aren't handed out again once the table is next modified.
+        let next = table.allocate_handle();
+        assert!(!unique.contains(&next));
+        for &handle in &reserved {
+            table.set(handle, 2);
+            assert_eq!(table.get(handle), Some(2));
+        }
+
+        table.invalidate(allocated);
+        table.release_handle(allocated);
+        assert_eq!(table.get(allocated), None);
+        assert!(!table.is_unset(allocated));
+    }
+
+    #[test]
+    fn cancel() {
+        let mut table = RawTable::<4>::new();
+        let set = table.allocate_handle();
+        table.set(set, 0);
+        let reserved = table.reserve_handle();
+        let allocated = table.allocate_handle();
+        assert!(!table.cancel_handle(set));
+
+        assert!(table.cancel_handle(reserved));
+        assert!(table.cancel_handle(allocated));
+        assert!(!table.cancel_handle(reserved));
+        assert_eq!(table.get(reserved), None);
+        assert_eq!(table.get(allocated), None);
+
+        // Cancelled slots go back on the free list, so the small table isn't exhausted.
+        let reused = [table.allocate_handle(), table.allocate_handle()];
+        assert!(!reused.contains(&reserved) && !reused.contains(&allocated));
+        assert_eq!(table.get(set), Some(0));
+    }
 }
@@ -3,30 +3,37 @@ use std::{
     collections::HashMap,
 };
 
-use crate::{blit::Blit, world};
-
-struct Part {
-    code: [u8; 4],
-    version: u32,
-    mask: u128,
-    align: usize,
-    width: usize,
+use crate::{
+    blit::Blit,
+    world::{self, new_sparse_storage, new_stable_sparse_storage, PartBitmap, SparseStorage},
+};
+
+pub(crate) struct Part {
+    pub(crate) name: &'static str,
+    pub(crate) align: usize,
+    pub(crate) width: usize,
+    /// Creates the storage for a sparse part, or `None` if the part is stored in chunks.
+    pub(crate) sparse: Option<fn() -> Box<dyn SparseStorage>>,
 }
 
-struct Blob {
-    code: [u8; 4],
-    version: u32,
-    align: usize,
-    width: usize,
+impl Part {
+    #[inline]
+    pub(crate) fn is_tag(&self) -> bool {
+        self.width == 0
+    }
+
+    #[inline]
+    pub(crate) fn is_sparse(&self) -> bool {
+        self.sparse.is_some()
+    }
 }
 
 #[derive(Default)]
 pub struct Registry {
     part_map: HashMap<TypeId, usize>,
+    resource_map: HashMap<TypeId, usize>,
+    /// Parts and resources, which share an index space.
     parts: Vec<Part>,
-
-    blob_map: HashMap<TypeId, usize>,
-    blobs: Vec<Blob>,
 }
 
 impl Registry {
@@ -34,18 +41,109 @@ impl Registry {
         Default::default()
     }
 
+    /// Registers a part type. Zero sized parts are tags, which things can have or not but which
+    /// take up no space in chunks.
     pub fn register_part<T: Blit + Any>(&mut self) {
+        let index = self.push_part::<T>();
+        self.part_map.insert(TypeId::of::<T>(), index);
+    }
+
+    /// Registers a part stored sparsely, outside of chunks, so adding or removing it doesn't move
+    /// a thing to another archtype. Suits parts which are toggled often, at the cost of a lookup
+    /// for each row queries visit.
+    pub fn register_sparse_part<T: Blit + Any>(&mut self) {
+        let index = self.push_part::<T>();
+        self.parts[index].sparse = Some(new_sparse_storage::<T>);
+        self.part_map.insert(TypeId::of::<T>(), index);
+    }
+
+    /// Registers a sparse part whose values never move while things keep them, so they can be
+    /// referenced by pointer from outside the world. Address space for a value per thing is
+    /// reserved up front, and only committed as things add the part.
+    pub fn register_stable_sparse_part<T: Blit + Any>(&mut self) {
+        let index = self.push_part::<T>();
+        self.parts[index].sparse = Some(new_stable_sparse_storage::<T>);
+        self.part_map.insert(TypeId::of::<T>(), index);
+    }
+
+    /// Registers a singleton resource stored on the world. Resources are indexed alongside parts,
+    /// so a `PartBitmap` can declare that a system reads or writes them, but they can't be added
+    /// to things.
+    pub fn register_resource<T: Blit + Any>(&mut self) {
+        let index = self.push_part::<T>();
+        self.resource_map.insert(TypeId::of::<T>(), index);
+    }
+
+    fn push_part<T: Blit + Any>(&mut self) -> usize {
         let next_index = self.parts.len();
         assert!(next_index < world::MAX_PART_TYPES);
-        self.part_map.insert(TypeId::of::<T>(), next_index);
         self.parts.push(Part {
-            code: [0; 4],
-            version: 0,
-            mask: 1 << next_index,
+            name: std::any::type_name::<T>(),
             align: std::mem::align_of::<T>(),
             width: std::mem::size_of::<T>(),
-        })
+            sparse: None,
+        });
+        next_index
     }
 
     pub fn register_type<T: Blit + Any>(&mut self) {}
+
+    /// Returns a bitmap of the part types in the tuple `P`, for declaring which parts a system
+    /// reads or writes.
+    pub fn part_bitmap<P: PartTypes>(&self) -> PartBitmap {
+        let mut bitmap = PartBitmap::default();
+        P::collect(self, &mut bitmap);
+        bitmap
+    }
+
+    #[inline]
+    pub(crate) fn part_index<T: Any>(&self) -> Option<usize> {
+        self.part_map.get(&TypeId::of::<T>()).copied()
+    }
+
+    #[inline]
+    pub(crate) fn resource_index<T: Any>(&self) -> Option<usize> {
+        self.resource_map.get(&TypeId::of::<T>()).copied()
+    }
+
+    #[inline]
+    pub(crate) fn part(&self, index: usize) -> &Part {
+        &self.parts[index]
+    }
+
+    /// Iterates over every registered part and resource, with its index.
+    pub(crate) fn parts(&self) -> impl Iterator<Item = (usize, &Part)> {
+        self.parts.iter().enumerate()
+    }
+}
+
+/// A tuple of registered part or resource types.
+pub trait PartTypes {
+    fn collect(registry: &Registry, bitmap: &mut PartBitmap);
 }
+
+macro_rules! impl_part_types_tuple {
+    ($($name:ident),*) => {
+        impl<$($name: Any),*> PartTypes for ($($name,)*) {
+            #[allow(unused_variables)]
+            fn collect(registry: &Registry, bitmap: &mut PartBitmap) {
+                $(bitmap.set(
+                    registry
+                        .part_index::<$name>()
+                        .or_else(|| registry.resource_index::<$name>())
+                        .expect("part type not registered"),
+                );)*
+            }
+        }
+    };
+}
+
+impl_part_types_tuple!();
+impl_part_types_tuple!(A);
+impl_part_types_tuple!(A, B);
+impl_part_types_tuple!(A, B, C);
+impl_part_types_tuple!(A, B, C, D);
+impl_part_types_tuple!(A, B, C, D, E);
+impl_part_types_tuple!(A, B, C, D, E, F);
+impl_part_types_tuple!(A, B, C, D, E, F, G);
+impl_part_types_tuple!(A, B, C, D, E, F, G, H);
@@ -58,6 +58,17 @@ impl<T, const N: usize> RingBuf<T, N> {
         std::mem::transmute::<&mut [MaybeUninit<_>; N], &mut [_; N]>(&mut *self.data)
     }
 
+    /// Returns the element `index` places from the front, without removing it.
+    #[inline]
+    pub fn get(&self, index: usize) -> Option<&T> {
+        if index < self.len() {
+            let index = self.tail.wrapping_add(index as u32) & Self::MASK;
+            unsafe { Some(&*self.data.get_unchecked(index as usize).as_ptr()) }
+        } else {
+            None
+        }
+    }
+
     #[inline]
     pub fn push_back(&mut self, value: T) {
         if self.is_full() {
@@ -89,7 +100,7 @@ impl<T, const N: usize> RingBuf<T, N> {
         }
     }
 
-    pub fn iter(&self) -> Iter<T> {
+    pub fn iter(&self) -> Iter<'_, T> {
         Iter::<T> {
             head: self.head,
             tail: self.tail,
@@ -98,7 +109,7 @@ impl<T, const N: usize> RingBuf<T, N> {
         }
     }
 
-    pub fn iter_mut(&mut self) -> IterMut<T> {
+    pub fn iter_mut(&mut self) -> IterMut<'_, T> {
         IterMut::<T> {
             head: self.head,
             tail: self.tail,
@@ -129,6 +140,12 @@ impl<T, const N: usize> RingBuf<T, N> {
     }
 }
 
+impl<T, const N: usize> Default for RingBuf<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T, const N: usize> Drop for RingBuf<T, N> {
     fn drop(&mut self) {
         /// Runs the destructor for all items in the slice when it gets dropped (normally or
@@ -307,6 +324,9 @@ mod tests {
                 assert_eq!(ring_buf.len(), (i + 1) as usize);
             }
             assert_eq!(ring_buf.len(), 200);
+            assert_eq!(ring_buf.get(0), Some(&0));
+            assert_eq!(ring_buf.get(150), Some(&150));
+            assert_eq!(ring_buf.get(200), None);
             for i in 0..200 {
                 assert_eq!(ring_buf.pop_front(), Some(i));
                 assert_eq!(ring_buf.len(), (199 - i) as usize);
@@ -366,12 +386,8 @@ mod tests {
                 assert_eq!(ring_buf.iter().count(), i + 1);
             }
 
-            {
-                let mut i = 0;
-                for x in ring_buf.iter() {
-                    assert_eq!(i, *x);
-                    i += 1
-                }
+            for (i, x) in ring_buf.iter().enumerate() {
+                assert_eq!(i, *x);
             }
 
             for i in 0..100 {
@@ -0,0 +1,432 @@
+use std::any::{type_name, Any};
+
+use crate::{
+    blit::Blit,
+    commands::CommandBuffer,
+    query::{Fetch, Filter, Query},
+    registry::Registry,
+    thread_pool::ThreadPool,
+    world::{PartBitmap, Resource, ResourceMut, ThingId, World},
+};
+
+type RunFn<'registry> = Box<
+    dyn FnMut(&mut SystemContext<'_, 'registry>, &mut CommandBuffer<'registry>) + Send + 'registry,
+>;
+
+/// The name of a system and the parts it declared it reads and writes.
+struct Declaration {
+    name: &'static str,
+    reads: PartBitmap,
+    writes: PartBitmap,
+}
+
+impl Declaration {
+    /// Returns true if the two systems can't run at the same time, because one writes a part the
+    /// other reads or writes.
+    fn conflicts_with(&self, other: &Declaration) -> bool {
+        !self.writes.is_disjoint_from(&other.reads)
+            || !self.writes.is_disjoint_from(&other.writes)
+            || !other.writes.is_disjoint_from(&self.reads)
+    }
+}
+
+struct System<'registry> {
+    declaration: Declaration,
+    run: RunFn<'registry>,
+    commands: CommandBuffer<'registry>,
+    stage: usize,
+}
+
+/// Runs systems over a world, a frame at a time.
+///
+/// Systems are grouped into stages. The systems within a stage have no conflicting access, and
+/// run in parallel on a thread pool. A system which conflicts with one added before it always runs
+/// in a later stage, so conflicting systems run in the order they were added.
+///
+/// Systems can't change the world structurally while running. Instead each system records changes
+/// in its own command buffer, and the buffers are applied at the end of its stage in the order the
+/// systems were added.
+pub struct Schedule<'registry> {
+    registry: &'registry Registry,
+    systems: Vec<System<'registry>>,
+    stage_count: usize,
+}
+
+impl<'registry> Schedule<'registry> {
+    pub fn new(registry: &'registry Registry) -> Self {
+        Self {
+            registry,
+            systems: Vec::new(),
+            stage_count: 0,
+        }
+    }
+
+    /// Adds a system which reads the parts in `reads` and writes the parts in `writes`, as
+    /// returned by `Registry::part_bitmap`. Parts which are written don't also need to be read.
+    pub fn add_system(
+        &mut self,
+        name: &'static str,
+        reads: PartBitmap,
+        writes: PartBitmap,
+        run: impl FnMut(&mut SystemContext<'_, 'registry>, &mut CommandBuffer<'registry>)
+            + Send
+            + 'registry,
+    ) {
+        let declaration = Declaration {
+            name,
+            reads,
+            writes,
+        };
+        // Place the system in the first stage after every system it conflicts with.
+        let stage = self
+            .systems
+            .iter()
+            .filter(|system| system.declaration.conflicts_with(&declaration))
+            .map(|system| system.stage + 1)
+            .max()
+            .unwrap_or(0);
+        self.stage_count = usize::max(self.stage_count, stage + 1);
+        self.systems.push(System {
+            declaration,
+            run: Box::new(run),
+            commands: CommandBuffer::new(self.registry),
+            stage,
+        });
+    }
+
+    /// Returns the names of the systems in each stage, in the order the stages run.
+    pub fn stages(&self) -> Vec<Vec<&'static str>> {
+        let mut stages = vec![Vec::new(); self.stage_count];
+        for system in &self.systems {
+            stages[system.stage].push(system.declaration.name);
+        }
+        stages
+    }
+
+    /// Runs every system once.
+    pub fn run(&mut self, world: &mut World<'registry>, pool: &ThreadPool) {
+        for stage in 0..self.stage_count {
+            let shared = SharedWorld(world);
+            pool.scope(|scope| {
+                for system in self
+                    .systems
+                    .iter_mut()
+                    .filter(|system| system.stage == stage)
+                {
+                    scope.spawn(move || {
+                        let mut context = SystemContext {
+                            world: shared.0,
+                            declaration: &system.declaration,
+                        };
+                        (system.run)(&mut context, &mut system.commands);
+                    });
+                }
+            });
+
+            for system in self
+                .systems
+                .iter_mut()
+                .filter(|system| system.stage == stage)
+            {
+                world.apply(&mut system.commands);
+            }
+        }
+    }
+}
+
+/// A reference to the world which can be sent to the systems of a stage.
+#[derive(Copy, Clone)]
+struct SharedWorld<'world, 'registry>(&'world World<'registry>);
+
+// SAFETY: systems only reach the world through a `SystemContext`, which checks every query and
+// resource borrow against the parts the system declared, including the change ticks read by
+// `Changed` filters. Systems in the same stage have non-conflicting declarations, so no part or
+// resource is written by one of them while another accesses it. The context only hands out
+// shared references to `Sync` values and exclusive references to `Send` values, so the values
+// themselves are safe to reach from the system's thread. Everything else the context exposes,
+// the tick and thing id reservation, is atomic.
+unsafe impl Send for SharedWorld<'_, '_> {}
+
+/// A running system's view of the world, limited to the parts it declared.
+pub struct SystemContext<'run, 'registry> {
+    world: &'run World<'registry>,
+    declaration: &'run Declaration,
+}
+
+impl<'run, 'registry> SystemContext<'run, 'registry> {
+    /// Creates a query over every thing which has all the parts fetched by `Q`. Systems run on
+    /// other threads, so parts read must be `Sync` and parts written must be `Send`.
+    ///
+    /// Panics if the query accesses parts the system didn't declare, or conflicts with another of
+    /// the system's queries.
+    pub fn query<Q: Fetch>(&self) -> Query<'_, 'registry, Q>
+    where
+        for<'c> Q::Chunk<'c>: Send,
+    {
+        self.query_filtered::<Q, ()>()
+    }
+
+    /// Creates a query over every thing which has all the parts fetched by `Q`, and which passes
+    /// the filter `F`. As for `query`, parts read must be `Sync` and parts written must be `Send`.
+    ///
+    /// Panics if the query accesses parts the system didn't declare, or conflicts with another of
+    /// the system's queries.
+    pub fn query_filtered<Q: Fetch, F: Filter>(&self) -> Query<'_, 'registry, Q, F>
+    where
+        for<'c> Q::Chunk<'c>: Send,
+    {
+        let declaration = self.declaration;
+        let access = Query::<Q, F>::access(self.world.registry);
+        let readable = declaration.reads.union(&declaration.writes);
+        if !readable.is_superset_of(&access.reads)
+            || !declaration.writes.is_superset_of(&access.writes)
+        {
+            undeclared_access::<Q>(declaration.name)
+        }
+        Query::new(self.world)
+    }
+
+    /// Borrows the resource of type `T`, if the world has one.
+    ///
+    /// Panics if the system didn't declare it reads or writes the resource.
+    pub fn resource<T: Blit + Any + Sync>(&self) -> Option<Resource<'_, T>> {
+        let declaration = self.declaration;
+        let index = self.world.registry.resource_index::<T>();
+        if !index.is_some_and(|index| {
+            declaration.reads.contains(index) || declaration.writes.contains(index)
+        }) {
+            undeclared_access::<T>(declaration.name)
+        }
+        self.world.resource::<T>()
+    }
+
+    /// Borrows the resource of type `T` mutably, if the world has one.
+    ///
+    /// Panics if the system didn't declare it writes the resource.
+    pub fn resource_mut<T: Blit + Any + Send>(&self) -> Option<ResourceMut<'_, T>> {
+        let declaration = self.declaration;
+        let index = self.world.registry.resource_index::<T>();
+        if !index.is_some_and(|index| declaration.writes.contains(index)) {
+            undeclared_access::<T>(declaration.name)
+        }
+        self.world.resource_mut::<T>()
+    }
+
+    /// Reserves an id for a thing spawned through the system's command buffer.
+    #[inline]
+    pub fn reserve_thing(&self) -> ThingId {
+        self.world.reserve_thing()
+    }
+
+    #[inline]
+    pub fn tick(&self) -> u32 {
+        self.world.tick()
+    }
+}
+
+#[cold]
+#[inline(never)]
+fn undeclared_access<T>(system: &str) {
+    panic!(
+        "system `{}` accesses `{}` in a way it didn't declare",
+        system,
+        type_name::<T>()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::Changed;
+
+    #[derive(Copy, Clone, PartialEq, Debug)]
+    struct PosX(f32);
+
+    #[derive(Copy, Clone, PartialEq, Debug)]
+    struct Speed(f32);
+
+    #[derive(Copy, Clone, PartialEq, Debug)]
+    struct Health(u32);
+
+    unsafe impl Blit for PosX {}
+    unsafe impl Blit for Speed {}
+    unsafe impl Blit for Health {}
+
+    fn registry() -> Registry {
+        let mut registry = Registry::new();
+        registry.register_part::<PosX>();
+        registry.register_part::<Speed>();
+        registry.register_part::<Health>();
+        registry
+    }
+
+    #[test]
+    fn stages() {
+        let registry = registry();
+        let mut schedule = Schedule::new(&registry);
+        let none = registry.part_bitmap::<()>();
+        let pos = registry.part_bitmap::<(PosX,)>();
+        let speed = registry.part_bitmap::<(Speed,)>();
+        let health = registry.part_bitmap::<(Health,)>();
+
+        schedule.add_system("move", speed.clone(), pos.clone(), |_, _| {});
+        schedule.add_system("heal", none.clone(), health.clone(), |_, _| {});
+        schedule.add_system("render", pos.clone(), none.clone(), |_, _| {});
+        schedule.add_system("damage", pos.clone(), health.clone(), |_, _| {});
+        schedule.add_system("accelerate", none.clone(), speed.clone(), |_, _| {});
+        schedule.add_system("debug", health, none.clone(), |_, _| {});
+        schedule.add_system("idle", none.clone(), none, |_, _| {});
+
+        assert_eq!(
+            schedule.stages(),
+            vec![
+                vec!["move", "heal", "idle"],
+                vec!["render", "damage", "accelerate"],
+                vec!["debug"],
+            ]
+        );
+    }
+
+    #[test]
+    fn run() {
+        let registry = registry();
+        let mut world = World::new(&registry);
+        let mut factory = world.factory();
+        for i in 0..1000 {
+            factory
+                .thing()
+                .add_part(PosX(0.0))
+                .add_part(Speed(i as f32))
+                .add_part(Health(i))
+                .finish();
+        }
+
+        let mut schedule = Schedule::new(&registry);
+        schedule.add_system(
+            "move",
+            registry.part_bitmap::<(Speed,)>(),
+            registry.part_bitmap::<(PosX,)>(),
+            |context, _| {
+                for (x, speed) in context.query::<(&mut PosX, &Speed)>().iter() {
+                    x.0 += speed.0;
+                }
+            },
+        );
+        schedule.add_system(
+            "damage",
+            registry.part_bitmap::<()>(),
+            registry.part_bitmap::<(Health,)>(),
+            |context, commands| {
+                for (thing, health) in context.query::<(ThingId, &mut Health)>().iter() {
+                    health.0 = health.0.saturating_sub(10);
+                    if health.0 == 0 {
+                        commands.destroy(thing);
+                    }
+                }
+            },
+        );
+        schedule.add_system(
+            "spawn",
+            registry.part_bitmap::<(PosX,)>(),
+            registry.part_bitmap::<()>(),
+            |context, commands| {
+                let count = context.query::<&PosX>().iter().count();
+                if count < 1000 {
+                    let thing = context.reserve_thing();
+                    commands.spawn(thing).add_part(PosX(-1.0)).finish();
+                }
+            },
+        );
+        assert_eq!(schedule.stages().len(), 2);
+
+        let pool = ThreadPool::new(4);
+        schedule.run(&mut world, &pool);
+        // The spawn system runs after the things destroyed by the first stage are gone.
+        assert_eq!(world.query::<&Health>().iter().count(), 989);
+        assert_eq!(world.query::<&PosX>().iter().count(), 990);
+
+        schedule.run(&mut world, &pool);
+        assert_eq!(world.query::<&Health>().iter().count(), 979);
+        assert_eq!(world.query::<&PosX>().iter().count(), 981);
+
+        for (x, speed) in world.query::<(&PosX, &Speed)>().iter() {
+            assert_eq!(x.0, speed.0 * 2.0);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "system `move` accesses")]
+    fn undeclared_write() {
+        let registry = registry();
+        let mut world = World::new(&registry);
+        let mut schedule = Schedule::new(&registry);
+        schedule.add_system(
+            "move",
+            registry.part_bitmap::<(PosX, Speed)>(),
+            registry.part_bitmap::<()>(),
+            |context, _| {
+                context.query::<(&mut PosX, &Speed)>();
+            },
+        );
+        schedule.run(&mut world, &ThreadPool::new(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "system `follow` accesses")]
+    fn undeclared_filter_read() {
+        let registry = registry();
+        let mut world = World::new(&registry);
+        let mut schedule = Schedule::new(&registry);
+        schedule.add_system(
+            "follow",
+            registry.part_bitmap::<(PosX,)>(),
+            registry.part_bitmap::<()>(),
+            |context, _| {
+                context.query_filtered::<&PosX, Changed<Speed>>();
+            },
+        );
+        schedule.run(&mut world, &ThreadPool::new(1));
+    }
+
+    #[test]
+    fn resources() {
+        #[derive(Copy, Clone, PartialEq, Debug)]
+        struct Clock(u32);
+
+        unsafe impl Blit for Clock {}
+
+        let mut registry = registry();
+        registry.register_resource::<Clock>();
+        let mut world = World::new(&registry);
+        world.insert_resource(Clock(0));
+        world.factory().thing().add_part(Health(100)).finish();
+
+        let mut schedule = Schedule::new(&registry);
+        schedule.add_system(
+            "damage",
+            registry.part_bitmap::<(Clock,)>(),
+            registry.part_bitmap::<(Health,)>(),
+            |context, _| {
+                let clock = context.resource::<Clock>().unwrap();
+                for health in context.query::<&mut Health>().iter() {
+                    health.0 -= clock.0;
+                }
+            },
+        );
+        schedule.add_system(
+            "clock",
+            registry.part_bitmap::<()>(),
+            registry.part_bitmap::<(Clock,)>(),
+            |context, _| context.resource_mut::<Clock>().unwrap().0 += 1,
+        );
+        assert_eq!(schedule.stages(), vec![vec!["damage"], vec!["clock"]]);
+
+        let pool = ThreadPool::new(2);
+        for _ in 0..4 {
+            schedule.run(&mut world, &pool);
+        }
+        assert_eq!(*world.resource::<Clock>().unwrap(), Clock(4));
+        let health = world.query::<&Health>().iter().next().copied();
+        assert_eq!(health, Some(Health(100 - 6)));
+    }
+}
@@ -1,59 +1,512 @@
-use std::{ptr::NonNull, slice::SliceIndex};
+use std::{
+    alloc::{self, Layout},
+    marker::PhantomData,
+    mem::{needs_drop, size_of, MaybeUninit},
+    ptr::NonNull,
+};
 
-pub struct SparseVec<T> {
-    len: usize,
+use crate::{
+    bit_set::{self, BitSet, Words},
+    virtual_vec::VirtualVec,
+};
+
+/// Storage for the slots of a `SparseVec`, along with the words of its occupancy bitmap.
+pub trait Slots<T> {
+    type Words: Words;
+
+    fn capacity(&self) -> usize;
+
+    /// Pointer to the first slot.
+    fn as_ptr(&self) -> *mut T;
+
+    /// Makes room for at least `min_cap` slots, which may move the existing slots.
+    fn grow(&mut self, min_cap: usize);
+}
+
+/// Slots on the heap, which are moved by reallocating as the vector grows.
+pub struct HeapSlots<T> {
+    /// Always a multiple of 64.
     cap: usize,
-    pop: NonNull<u64>,
     ptr: NonNull<T>,
 }
 
-#[inline]
-fn has_entry_at_index(pop: &[u64], index: usize) -> bool {
-    if let Some(&word) = pop.get(index / 64) {
-        let mask = 1 << (index % 64);
-        word & mask != 0
-    } else {
-        false
+// The slots own their values, like a `Vec`.
+unsafe impl<T: Send> Send for HeapSlots<T> {}
+unsafe impl<T: Sync> Sync for HeapSlots<T> {}
+
+impl<T> Default for HeapSlots<T> {
+    fn default() -> Self {
+        Self {
+            cap: 0,
+            ptr: NonNull::dangling(),
+        }
+    }
+}
+
+impl<T> Slots<T> for HeapSlots<T> {
+    type Words = Vec<u64>;
+
+    #[inline]
+    fn capacity(&self) -> usize {
+        self.cap
+    }
+
+    #[inline]
+    fn as_ptr(&self) -> *mut T {
+        self.ptr.as_ptr()
+    }
+
+    /// Doubles the capacity to keep growth amortized.
+    #[cold]
+    #[inline(never)]
+    fn grow(&mut self, min_cap: usize) {
+        let cap = usize::max(min_cap, self.cap * 2);
+        let cap = usize::max(cap.div_ceil(64) * 64, 64);
+
+        // Zero sized values need no storage, only the occupancy bitmap.
+        if size_of::<T>() != 0 {
+            let layout = Layout::array::<T>(cap).expect("capacity overflow");
+            let ptr = unsafe { alloc::alloc(layout) }.cast::<T>();
+            let ptr = NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(layout));
+            if self.cap != 0 {
+                unsafe { std::ptr::copy_nonoverlapping(self.ptr.as_ptr(), ptr.as_ptr(), self.cap) }
+                self.dealloc();
+            }
+            self.ptr = ptr;
+        }
+        self.cap = cap;
+    }
+}
+
+impl<T> HeapSlots<T> {
+    /// Frees the storage, without dropping any values.
+    fn dealloc(&mut self) {
+        if size_of::<T>() != 0 && self.cap != 0 {
+            unsafe {
+                alloc::dealloc(
+                    self.ptr.as_ptr().cast(),
+                    Layout::array::<T>(self.cap).unwrap(),
+                )
+            }
+        }
+    }
+}
+
+impl<T> Drop for HeapSlots<T> {
+    fn drop(&mut self) {
+        self.dealloc();
     }
 }
 
-fn find_empty_slot() -> usize {
-    todo!()
+/// Slots in a memory mapping reserved up front, which never move. Growing only commits pages.
+pub struct VirtualSlots<T> {
+    /// `None` for zero sized values, which need no storage.
+    values: Option<VirtualVec<MaybeUninit<T>>>,
+    cap: usize,
+    map: usize,
+}
+
+impl<T> VirtualSlots<T> {
+    pub fn new(map: usize) -> Self {
+        Self {
+            values: (size_of::<T>() != 0).then(|| VirtualVec::new(map)),
+            cap: 0,
+            map,
+        }
+    }
 }
 
+impl<T> Slots<T> for VirtualSlots<T> {
+    type Words = VirtualVec<u64>;
+
+    #[inline]
+    fn capacity(&self) -> usize {
+        self.cap
+    }
+
+    #[inline]
+    fn as_ptr(&self) -> *mut T {
+        match &self.values {
+            Some(values) => values.as_ptr() as *mut T,
+            None => NonNull::dangling().as_ptr(),
+        }
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn grow(&mut self, min_cap: usize) {
+        if min_cap > self.map {
+            mapping_exhausted(min_cap, self.map)
+        }
+        self.cap = match &mut self.values {
+            Some(values) => {
+                values.reserve(min_cap - values.len());
+                // SAFETY: the slots are `MaybeUninit`, and the vector tracks which are occupied.
+                unsafe { values.set_len(values.capacity()) }
+                values.len()
+            }
+            None => min_cap,
+        };
+    }
+}
+
+#[cold]
+#[inline(never)]
+fn mapping_exhausted(index: usize, map: usize) {
+    panic!(
+        "slot `{}` beyond SparseVec mapping of `{}` slots",
+        index - 1,
+        map
+    );
+}
+
+/// A vector whose slots may each be empty or hold a value, with a bitmap recording which slots
+/// are occupied. The slots are kept in `S`, which decides whether they move as the vector grows.
+pub struct SparseVec<T, S: Slots<T> = HeapSlots<T>> {
+    /// Number of slots in use, one past the highest slot which has held a value.
+    len: usize,
+    slots: S,
+    /// Occupancy bitmap, with a bit set for each occupied slot.
+    pop: BitSet<S::Words>,
+    _marker: PhantomData<T>,
+}
+
+/// A `SparseVec` whose values never move, so pointers to them stay valid until they're removed.
+pub type StableSparseVec<T> = SparseVec<T, VirtualSlots<T>>;
+
+unsafe impl<T: Send, S: Slots<T> + Send> Send for SparseVec<T, S> where S::Words: Send {}
+unsafe impl<T: Sync, S: Slots<T> + Sync> Sync for SparseVec<T, S> where S::Words: Sync {}
+
 impl<T> SparseVec<T> {
     pub fn new() -> Self {
         Self {
             len: 0,
-            cap: 0,
-            pop: NonNull::dangling(),
-            ptr: NonNull::dangling(),
+            slots: HeapSlots::default(),
+            pop: BitSet::new(),
+            _marker: PhantomData,
         }
     }
+}
 
+impl<T> StableSparseVec<T> {
+    /// Creates a vector with address space reserved for `map` slots, which is all it can hold.
+    pub fn with_mapping(map: usize) -> Self {
+        Self {
+            len: 0,
+            slots: VirtualSlots::new(map),
+            pop: BitSet::with_mapping(map),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T, S: Slots<T>> SparseVec<T, S> {
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.slots.capacity()
+    }
+
+    /// Stores `value` in the first empty slot, reusing holes left by `remove` before growing, and
+    /// returns the slot's index.
     pub fn insert(&mut self, value: T) -> usize {
-        todo!()
+        // Slots at or past `len` are always empty, so the first empty slot is at most `len`.
+        let index = self.pop.first_unset();
+        self.insert_at(index, value);
+        index
+    }
+
+    /// Stores `value` in the slot `index`, growing the vector if needed, and returns the value
+    /// previously in the slot.
+    pub fn insert_at(&mut self, index: usize, value: T) -> Option<T> {
+        if index >= self.slots.capacity() {
+            self.slots.grow(index + 1);
+        }
+        self.len = usize::max(self.len, index + 1);
+
+        let previous = self.remove(index);
+        unsafe { self.slots.as_ptr().add(index).write(value) }
+        self.pop.set(index);
+        previous
     }
 
-    pub fn remove<I: SliceIndex<T>>(&mut self, index: I) -> T {
-        todo!()
+    /// Takes the value out of the slot `index`, leaving it empty.
+    pub fn remove(&mut self, index: usize) -> Option<T> {
+        if self.pop.unset(index) {
+            unsafe { Some(self.slots.as_ptr().add(index).read()) }
+        } else {
+            None
+        }
+    }
+
+    #[inline]
+    pub fn contains(&self, index: usize) -> bool {
+        self.pop.contains(index)
     }
 
     #[inline]
     pub fn get(&self, index: usize) -> Option<&T> {
-        if index >= self.len {
-            return None;
+        if self.contains(index) {
+            unsafe { Some(&*self.slots.as_ptr().add(index)) }
+        } else {
+            None
         }
-
-        todo!()
     }
 
     #[inline]
     pub fn get_mut(&mut self, index: usize) -> Option<&mut T> {
-        if index >= self.len {
-            return None;
+        if self.contains(index) {
+            unsafe { Some(&mut *self.slots.as_ptr().add(index)) }
+        } else {
+            None
+        }
+    }
+
+    /// Pointer to the first slot. Slots which are occupied hold a valid value, and may be written
+    /// through the pointer while nothing else borrows them, even though `self` is shared.
+    #[inline]
+    pub(crate) fn as_ptr(&self) -> *mut T {
+        self.slots.as_ptr()
+    }
+
+    /// The set of occupied slots.
+    #[inline]
+    pub fn occupancy(&self) -> &BitSet<S::Words> {
+        &self.pop
+    }
+
+    /// Iterates over the indices of occupied slots in ascending order. Only non-empty occupancy
+    /// words are visited, so the cost depends on the number of values rather than the capacity.
+    #[inline]
+    pub fn indices(&self) -> bit_set::Iter<'_> {
+        self.pop.iter()
+    }
+
+    /// Iterates over the occupied slots in ascending order, with their indices.
+    #[inline]
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            indices: self.indices(),
+            ptr: self.slots.as_ptr(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Iterates mutably over the occupied slots in ascending order, with their indices.
+    #[inline]
+    pub fn iter_mut(&mut self) -> IterMut<'_, T> {
+        IterMut {
+            indices: self.pop.iter(),
+            ptr: self.slots.as_ptr(),
+            _marker: PhantomData,
         }
+    }
+}
+
+pub struct Iter<'a, T> {
+    indices: bit_set::Iter<'a>,
+    ptr: *const T,
+    _marker: PhantomData<&'a T>,
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = (usize, &'a T);
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        let index = self.indices.next()?;
+        unsafe { Some((index, &*self.ptr.add(index))) }
+    }
+}
+
+pub struct IterMut<'a, T> {
+    indices: bit_set::Iter<'a>,
+    ptr: *mut T,
+    _marker: PhantomData<&'a mut T>,
+}
+
+impl<'a, T> Iterator for IterMut<'a, T> {
+    type Item = (usize, &'a mut T);
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        let index = self.indices.next()?;
+        // Each index is yielded once, so the references don't alias.
+        unsafe { Some((index, &mut *self.ptr.add(index))) }
+    }
+}
 
-        todo!()
+impl<T> Default for SparseVec<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, S: Slots<T>> Drop for SparseVec<T, S> {
+    fn drop(&mut self) {
+        if needs_drop::<T>() {
+            for index in self.pop.iter() {
+                unsafe { self.slots.as_ptr().add(index).drop_in_place() }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc};
+
+    use super::*;
+
+    #[test]
+    fn insert_remove() {
+        let mut values = SparseVec::new();
+        assert!(values.is_empty());
+        for i in 0..200 {
+            assert_eq!(values.insert(i), i);
+        }
+        assert_eq!(values.len(), 200);
+        assert!(values.capacity() >= 200 && values.capacity() % 64 == 0);
+        assert_eq!(values.get(150), Some(&150));
+        assert_eq!(values.get(200), None);
+
+        *values.get_mut(3).unwrap() = 1000;
+        assert_eq!(values.insert_at(3, 3), Some(1000));
+        assert_eq!(values.remove(3), Some(3));
+        assert_eq!(values.remove(3), None);
+        assert!(!values.contains(3));
+
+        // Inserting far past the end grows to fit, leaving the slots between empty.
+        assert_eq!(values.insert_at(1000, 1), None);
+        assert_eq!(values.len(), 1001);
+        assert!(values.capacity() >= 1001);
+        assert_eq!(values.get(500), None);
+        assert_eq!(values.get(150), Some(&150));
+    }
+
+    #[test]
+    fn send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<SparseVec<u32>>();
+        assert_send_sync::<StableSparseVec<u32>>();
+    }
+
+    #[test]
+    fn reuse_holes() {
+        let mut values = SparseVec::new();
+        for i in 0..130 {
+            values.insert(i);
+        }
+        values.remove(129);
+        values.remove(64);
+        values.remove(5);
+
+        // Holes are filled lowest first, before the vector grows.
+        assert_eq!(values.insert(0), 5);
+        assert_eq!(values.insert(0), 64);
+        assert_eq!(values.insert(0), 129);
+        assert_eq!(values.insert(0), 130);
+        assert_eq!(values.len(), 131);
+
+        let mut tags = SparseVec::new();
+        assert_eq!(tags.insert(()), 0);
+        assert_eq!(tags.insert(()), 1);
+        tags.remove(0);
+        assert_eq!(tags.insert(()), 0);
+        assert_eq!(tags.get(1), Some(&()));
+    }
+
+    #[test]
+    fn iterate() {
+        let mut values = SparseVec::new();
+        assert_eq!(values.indices().next(), None);
+
+        for index in [0, 1, 63, 64, 200, 4095] {
+            values.insert_at(index, index * 2);
+        }
+        values.remove(1);
+        assert_eq!(
+            values.indices().collect::<Vec<_>>(),
+            vec![0, 63, 64, 200, 4095]
+        );
+
+        for (index, value) in values.iter_mut() {
+            *value += index;
+        }
+        assert!(values.iter().all(|(index, &value)| value == index * 3));
+        assert_eq!(values.iter().count(), 5);
+    }
+
+    #[test]
+    fn stable() {
+        let mut values = StableSparseVec::with_mapping(1 << 20);
+        values.insert_at(0, 0u64);
+        let first = values.get(0).unwrap() as *const u64;
+        for i in 1..100_000 {
+            values.insert(i);
+        }
+        values.insert_at((1 << 20) - 1, 1);
+        assert_eq!(values.get(0).unwrap() as *const u64, first);
+        assert_eq!(values.iter().count(), 100_001);
+        assert_eq!(values.remove(99_999), Some(99_999));
+        assert_eq!(values.insert(7), 99_999);
+
+        let mut tags = StableSparseVec::with_mapping(10);
+        tags.insert_at(9, ());
+        assert!(tags.contains(9));
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            tags.insert_at(10, ());
+        }));
+        let message = *result.unwrap_err().downcast::<String>().unwrap();
+        assert_eq!(message, "slot `10` beyond SparseVec mapping of `10` slots");
+    }
+
+    #[test]
+    fn drop_counts() {
+        struct Counted(Rc<Cell<usize>>);
+
+        impl Drop for Counted {
+            fn drop(&mut self) {
+                self.0.set(self.0.get() + 1);
+            }
+        }
+
+        let drops = Rc::new(Cell::new(0));
+        let mut values = SparseVec::new();
+        for _ in 0..100 {
+            values.insert(Counted(drops.clone()));
+        }
+        for i in (0..100).step_by(4) {
+            drop(values.remove(i));
+        }
+        assert_eq!(drops.get(), 25);
+
+        // Overwriting a slot drops the value it held.
+        drop(values.insert_at(1, Counted(drops.clone())));
+        assert_eq!(drops.get(), 26);
+
+        // Growing moves values without dropping them.
+        values.insert_at(500, Counted(drops.clone()));
+        assert_eq!(drops.get(), 26);
+
+        drop(values);
+        assert_eq!(drops.get(), 26 + 76);
+
+        let mut values = StableSparseVec::with_mapping(1000);
+        for _ in 0..10 {
+            values.insert(Counted(drops.clone()));
+        }
+        drop(values.remove(3));
+        drop(values);
+        assert_eq!(drops.get(), 26 + 76 + 10);
     }
 }
@@ -0,0 +1,246 @@
+use std::{
+    any::Any,
+    collections::VecDeque,
+    marker::PhantomData,
+    panic::{catch_unwind, resume_unwind, AssertUnwindSafe},
+    sync::{Arc, Condvar, Mutex},
+    thread::{self, JoinHandle},
+};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+struct Queue {
+    jobs: VecDeque<Job>,
+    shutdown: bool,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    available: Condvar,
+}
+
+impl Shared {
+    fn push(&self, job: Job) {
+        self.queue.lock().unwrap().jobs.push_back(job);
+        self.available.notify_one();
+    }
+
+    fn try_pop(&self) -> Option<Job> {
+        self.queue.lock().unwrap().jobs.pop_front()
+    }
+}
+
+/// A fixed set of worker threads which run jobs spawned within a `scope`.
+pub struct ThreadPool {
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl ThreadPool {
+    pub fn new(threads: usize) -> Self {
+        assert!(threads > 0, "thread pool needs at least one thread");
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue {
+                jobs: VecDeque::new(),
+                shutdown: false,
+            }),
+            available: Condvar::new(),
+        });
+        let workers = (0..threads)
+            .map(|_| {
+                let shared = shared.clone();
+                thread::spawn(move || worker(&shared))
+            })
+            .collect();
+        Self { shared, workers }
+    }
+
+    #[inline]
+    pub fn threads(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Runs `f`, which may spawn jobs borrowing from the enclosing stack frame, and waits for
+    /// every job it spawned before returning. While waiting the calling thread runs queued jobs
+    /// itself, so scopes may be nested within jobs without starving the pool.
+    ///
+    /// If any job panics, the panic is resumed on the calling thread once every job is done.
+    pub fn scope<'env, R>(&self, f: impl FnOnce(&Scope<'_, 'env>) -> R) -> R {
+        let scope = Scope {
+            shared: &self.shared,
+            state: Arc::new(ScopeState {
+                pending: Mutex::new(0),
+                done: Condvar::new(),
+                panic: Mutex::new(None),
+            }),
+            _marker: PhantomData,
+        };
+        let result = catch_unwind(AssertUnwindSafe(|| f(&scope)));
+        // Jobs borrow from the caller's stack, so we must wait for them even if `f` panicked.
+        scope.wait();
+        if let Some(panic) = scope.state.panic.lock().unwrap().take() {
+            resume_unwind(panic);
+        }
+        match result {
+            Ok(result) => result,
+            Err(panic) => resume_unwind(panic),
+        }
+    }
+}
+
+impl Default for ThreadPool {
+    /// Creates a pool with a thread for each core.
+    fn default() -> Self {
+        Self::new(thread::available_parallelism().map_or(1, |threads| threads.get()))
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().shutdown = true;
+        self.shared.available.notify_all();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn worker(shared: &Shared) {
+    loop {
+        let job = {
+            let queue = shared.queue.lock().unwrap();
+            let mut queue = shared
+                .available
+                .wait_while(queue, |queue| queue.jobs.is_empty() && !queue.shutdown)
+                .unwrap();
+            match queue.jobs.pop_front() {
+                Some(job) => job,
+                None => return,
+            }
+        };
+        job();
+    }
+}
+
+struct ScopeState {
+    pending: Mutex<usize>,
+    done: Condvar,
+    panic: Mutex<Option<Box<dyn Any + Send>>>,
+}
+
+/// Spawns jobs onto a thread pool which may borrow anything outliving `'env`.
+pub struct Scope<'pool, 'env> {
+    shared: &'pool Arc<Shared>,
+    state: Arc<ScopeState>,
+    _marker: PhantomData<&'env mut &'env ()>,
+}
+
+impl<'pool, 'env> Scope<'pool, 'env> {
+    pub fn spawn<F: FnOnce() + Send + 'env>(&self, f: F) {
+        *self.state.pending.lock().unwrap() += 1;
+        let state = self.state.clone();
+        let job: Box<dyn FnOnce() + Send + 'env> = Box::new(move || {
+            if let Err(panic) = catch_unwind(AssertUnwindSafe(f)) {
+                state.panic.lock().unwrap().get_or_insert(panic);
+            }
+            let mut pending = state.pending.lock().unwrap();
+            *pending -= 1;
+            if *pending == 0 {
+                state.done.notify_all();
+            }
+        });
+        // SAFETY: `ThreadPool::scope` doesn't return until every job spawned in the scope has
+        // finished, so nothing the job borrows can go away while it runs.
+        let job = unsafe { std::mem::transmute::<Box<dyn FnOnce() + Send + 'env>, Job>(job) };
+        self.shared.push(job);
+    }
+
+    fn wait(&self) {
+        loop {
+            if *self.state.pending.lock().unwrap() == 0 {
+                return;
+            }
+            match self.shared.try_pop() {
+                Some(job) => job(),
+                None => {
+                    // Every job of ours has been picked up, so just wait for them to finish.
+                    let pending = self.state.pending.lock().unwrap();
+                    let _pending = self
+                        .state
+                        .done
+                        .wait_while(pending, |pending| *pending != 0)
+                        .unwrap();
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn scoped_jobs() {
+        let pool = ThreadPool::new(4);
+        let mut values = vec![0; 1000];
+        pool.scope(|scope| {
+            for (i, chunk) in values.chunks_mut(10).enumerate() {
+                scope.spawn(move || chunk.fill(i));
+            }
+        });
+        for (i, value) in values.iter().enumerate() {
+            assert_eq!(*value, i / 10);
+        }
+    }
+
+    #[test]
+    fn nested_scopes() {
+        // More nested scopes than threads must not deadlock.
+        let pool = ThreadPool::new(2);
+        let count = AtomicUsize::new(0);
+        pool.scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    pool.scope(|scope| {
+                        for _ in 0..8 {
+                            scope.spawn(|| {
+                                count.fetch_add(1, Ordering::Relaxed);
+                            });
+                        }
+                    })
+                });
+            }
+        });
+        assert_eq!(count.load(Ordering::Relaxed), 64);
+    }
+
+    #[test]
+    fn job_panics() {
+        let pool = ThreadPool::new(2);
+        let count = AtomicUsize::new(0);
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            pool.scope(|scope| {
+                scope.spawn(|| panic!("job failed"));
+                for _ in 0..10 {
+                    scope.spawn(|| {
+                        count.fetch_add(1, Ordering::Relaxed);
+                    });
+                }
+            })
+        }));
+        assert!(result.is_err());
+        assert_eq!(count.load(Ordering::Relaxed), 10);
+
+        // The pool is still usable afterwards.
+        pool.scope(|scope| {
+            scope.spawn(|| {
+                count.fetch_add(1, Ordering::Relaxed);
+            })
+        });
+        assert_eq!(count.load(Ordering::Relaxed), 11);
+    }
+}
@@ -0,0 +1,196 @@
+use std::{
+    alloc::{handle_alloc_error, Layout},
+    ptr::null_mut,
+};
+
+#[cold]
+#[inline(never)]
+fn capacity_overflow() -> ! {
+    panic!("vector part capacity overflow");
+}
+
+/// Out-of-line storage for the elements of a vector part.
+///
+/// Lives directly in a chunk column, so it's moved around with a plain memcpy along with the rest
+/// of its row. The element layout isn't stored, and must be passed to every operation.
+#[repr(C)]
+pub(crate) struct RawVector {
+    ptr: *mut u8,
+    len: u32,
+    cap: u32,
+}
+
+impl RawVector {
+    pub(crate) const EMPTY: RawVector = RawVector {
+        ptr: null_mut(),
+        len: 0,
+        cap: 0,
+    };
+
+    #[inline]
+    pub(crate) fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    /// # Safety
+    ///
+    /// The vector must contain elements of type `T`.
+    #[inline]
+    pub(crate) unsafe fn as_slice<T>(&self) -> &[T] {
+        if self.ptr.is_null() {
+            &[]
+        } else {
+            std::slice::from_raw_parts(self.ptr.cast(), self.len())
+        }
+    }
+
+    /// # Safety
+    ///
+    /// The vector must contain elements of type `T`.
+    #[inline]
+    pub(crate) unsafe fn as_mut_slice<T>(&mut self) -> &mut [T] {
+        if self.ptr.is_null() {
+            &mut []
+        } else {
+            std::slice::from_raw_parts_mut(self.ptr.cast(), self.len())
+        }
+    }
+
+    fn grow(&mut self, align: usize, width: usize, additional: usize) {
+        let len = self.len();
+        let required = len
+            .checked_add(additional)
+            .unwrap_or_else(|| capacity_overflow());
+        if required > u32::MAX as usize {
+            capacity_overflow()
+        }
+
+        // Zero sized elements never need any storage, just a well aligned pointer.
+        if width == 0 {
+            self.ptr = align as *mut u8;
+            self.cap = u32::MAX;
+            return;
+        }
+
+        let cap = usize::max(usize::max(self.cap as usize * 2, required), 4);
+        let cap = usize::min(cap, u32::MAX as usize);
+        let layout =
+            Layout::from_size_align(width * cap, align).unwrap_or_else(|_| capacity_overflow());
+        let ptr = unsafe {
+            if self.ptr.is_null() {
+                std::alloc::alloc(layout)
+            } else {
+                let old_layout =
+                    Layout::from_size_align_unchecked(width * self.cap as usize, align);
+                std::alloc::realloc(self.ptr, old_layout, layout.size())
+            }
+        };
+        if ptr.is_null() {
+            handle_alloc_error(layout)
+        }
+        self.ptr = ptr;
+        self.cap = cap as u32;
+    }
+
+    /// Appends `count` elements of `width` bytes each, copied from `data`.
+    ///
+    /// # Safety
+    ///
+    /// `align` and `width` must match the vector's element type, and `data` must be valid for
+    /// reads of `count * width` bytes.
+    pub(crate) unsafe fn extend(
+        &mut self,
+        align: usize,
+        width: usize,
+        data: *const u8,
+        count: usize,
+    ) {
+        if count == 0 {
+            return;
+        }
+        if count > (self.cap - self.len) as usize {
+            self.grow(align, width, count);
+        }
+        let dst = self.ptr.add(self.len() * width);
+        std::ptr::copy_nonoverlapping(data, dst, count * width);
+        self.len += count as u32;
+    }
+
+    /// Removes the last element, copying its bytes to `out`. Returns false if the vector was
+    /// empty.
+    ///
+    /// # Safety
+    ///
+    /// `width` must match the vector's element type, and `out` must be valid for writes of
+    /// `width` bytes.
+    pub(crate) unsafe fn pop(&mut self, width: usize, out: *mut u8) -> bool {
+        if self.len == 0 {
+            return false;
+        }
+        self.len -= 1;
+        std::ptr::copy_nonoverlapping(self.ptr.add(self.len() * width), out, width);
+        true
+    }
+
+    /// Releases the vector's storage, leaving it empty.
+    ///
+    /// # Safety
+    ///
+    /// `align` and `width` must match the vector's element type.
+    pub(crate) unsafe fn free(&mut self, align: usize, width: usize) {
+        if !self.ptr.is_null() && width != 0 {
+            let layout = Layout::from_size_align_unchecked(width * self.cap as usize, align);
+            std::alloc::dealloc(self.ptr, layout);
+        }
+        *self = Self::EMPTY;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::{align_of, size_of};
+
+    use super::*;
+
+    #[test]
+    fn push_pop() {
+        let (align, width) = (align_of::<u64>(), size_of::<u64>());
+        let mut vector = RawVector::EMPTY;
+        unsafe {
+            assert!(vector.as_slice::<u64>().is_empty());
+            for i in 0..100u64 {
+                vector.extend(align, width, (&i as *const u64).cast(), 1);
+            }
+            assert_eq!(vector.len(), 100);
+            assert!(vector.as_slice::<u64>().iter().copied().eq(0..100));
+
+            let more = [100u64, 101, 102];
+            vector.extend(align, width, more.as_ptr().cast(), more.len());
+            assert_eq!(vector.as_slice::<u64>()[100..], more);
+
+            let mut out = 0u64;
+            for i in (0..103).rev() {
+                assert!(vector.pop(width, (&mut out as *mut u64).cast()));
+                assert_eq!(out, i);
+            }
+            assert!(!vector.pop(width, (&mut out as *mut u64).cast()));
+            assert_eq!(vector.len(), 0);
+
+            vector.free(align, width);
+        }
+    }
+
+    #[test]
+    fn zero_sized() {
+        let mut vector = RawVector::EMPTY;
+        unsafe {
+            for _ in 0..10 {
+                vector.extend(1, 0, [].as_ptr(), 1);
+            }
+            assert_eq!(vector.as_slice::<()>().len(), 10);
+            assert!(vector.pop(0, [].as_mut_ptr()));
+            assert_eq!(vector.len(), 9);
+            vector.free(1, 0);
+        }
+    }
+}
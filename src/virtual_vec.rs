@@ -20,6 +20,10 @@ pub struct VirtualVec<T> {
     _marker: PhantomData<T>,
 }
 
+// The mapping owns its elements, like a `Vec`.
+unsafe impl<T: Send> Send for VirtualVec<T> {}
+unsafe impl<T: Sync> Sync for VirtualVec<T> {}
+
 #[cold]
 #[inline(never)]
 fn bounds_check_failed(index: usize, len: usize) {
@@ -117,6 +121,15 @@ impl<T> VirtualVec<T> {
         self.ptr.as_ptr()
     }
 
+    /// # Safety
+    ///
+    /// `len` must be at most the capacity, and the elements up to `len` must be initialized.
+    #[inline]
+    pub unsafe fn set_len(&mut self, len: usize) {
+        debug_assert!(len <= self.cap);
+        self.len = len;
+    }
+
     pub fn truncate(&mut self, len: usize) {
         unsafe {
             if len >= self.len {
@@ -230,7 +243,7 @@ impl<T> VirtualVec<T> {
         }
 
         // SAFETY: can't wrap, but we use wrapping add so we don't need to check.
-        let min_capacity = self.cap.wrapping_add(additional);
+        let min_capacity = self.len.wrapping_add(additional);
         if min_capacity > self.map {
             capacity_overflow(additional)
         }
@@ -314,7 +327,7 @@ mod tests {
     #[test]
     fn create_destroy() {
         let vec = VirtualVec::<i32>::new(4096);
-        assert_eq!(vec.is_empty(), true);
+        assert!(vec.is_empty());
         assert_eq!(vec.mapping(), 4096);
         assert_eq!(vec.capacity(), 0);
         assert_eq!(vec.len(), 0);
@@ -1,4 +1,26 @@
-use crate::{raw_table::RawTable, registry::Registry, ring_buf::RingBuf, virtual_vec::VirtualVec};
+use std::{
+    alloc::Layout,
+    any::{type_name, Any},
+    cmp::Reverse,
+    collections::{hash_map::Entry, HashMap},
+    marker::PhantomData,
+    mem::{align_of, size_of, MaybeUninit},
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+};
+
+use crate::{
+    bit_set::BitSlice,
+    blit::Blit,
+    commands::CommandBuffer,
+    query::{Fetch, Filter, Query},
+    raw_table::RawTable,
+    registry::{Part, Registry},
+    sparse_vec::{Slots, SparseVec, StableSparseVec},
+    vector::RawVector,
+    virtual_vec::VirtualVec,
+};
 
 pub const MAX_PART_TYPES: usize = 256;
 
@@ -6,84 +28,2659 @@ const MAX_ARCHTYPES: usize = 1 << 14;
 const MAX_CHUNKS: usize = 1 << 17;
 const MAX_THINGS: usize = 1 << 20;
 
-const TABLE_CACHE_MIN_SIZE: usize = 128;
-const TABLE_CACHE_FILL_SIZE: usize = 256;
-const TABLE_CACHE_SIZE: usize = 512;
-
 const CHUNK_SIZE_BYTES: usize = 16 * 1024;
+const CHUNK_ALIGN: usize = 64;
 
-#[derive(Clone)]
+/// A set of part indices, as assigned by the `Registry`. Archtype keys, query access and system
+/// declarations are all expressed as part bitmaps.
+#[derive(Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
 pub struct PartBitmap {
     parts: [u64; MAX_PART_TYPES / 64],
 }
 
-#[derive(Clone)]
+impl PartBitmap {
+    #[inline]
+    pub fn set(&mut self, index: usize) {
+        self.parts[index / 64] |= 1 << (index % 64)
+    }
+
+    #[inline]
+    pub fn clear(&mut self, index: usize) {
+        self.parts[index / 64] &= !(1 << (index % 64))
+    }
+
+    #[inline]
+    pub fn contains(&self, index: usize) -> bool {
+        self.parts[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.parts.iter().all(|&word| word == 0)
+    }
+
+    /// Returns the number of parts in the bitmap.
+    #[inline]
+    pub fn count(&self) -> usize {
+        self.parts
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    #[inline]
+    pub fn is_superset_of(&self, other: &PartBitmap) -> bool {
+        self.parts
+            .iter()
+            .zip(other.parts.iter())
+            .all(|(a, b)| a & b == *b)
+    }
+
+    #[inline]
+    pub fn is_disjoint_from(&self, other: &PartBitmap) -> bool {
+        self.parts
+            .iter()
+            .zip(other.parts.iter())
+            .all(|(a, b)| a & b == 0)
+    }
+
+    /// Iterates over the indices of the parts in the bitmap, in ascending order.
+    pub fn iter_set_bits(&self) -> impl Iterator<Item = usize> + '_ {
+        self.parts
+            .iter()
+            .enumerate()
+            .flat_map(|(word_index, &word)| {
+                let mut word = word;
+                std::iter::from_fn(move || {
+                    if word == 0 {
+                        return None;
+                    }
+                    let bit = word.trailing_zeros() as usize;
+                    word &= word - 1;
+                    Some(word_index * 64 + bit)
+                })
+            })
+    }
+
+    /// Returns the parts in either bitmap.
+    #[inline]
+    pub fn union(&self, other: &PartBitmap) -> PartBitmap {
+        self.combine(other, |a, b| a | b)
+    }
+
+    /// Returns the parts in both bitmaps.
+    #[inline]
+    pub fn intersection(&self, other: &PartBitmap) -> PartBitmap {
+        self.combine(other, |a, b| a & b)
+    }
+
+    /// Returns the parts in `self` but not in `other`.
+    #[inline]
+    pub fn difference(&self, other: &PartBitmap) -> PartBitmap {
+        self.combine(other, |a, b| a & !b)
+    }
+
+    #[inline]
+    fn combine(&self, other: &PartBitmap, f: impl Fn(u64, u64) -> u64) -> PartBitmap {
+        let mut result = self.clone();
+        for (a, &b) in result.parts.iter_mut().zip(other.parts.iter()) {
+            *a = f(*a, b);
+        }
+        result
+    }
+}
+
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
 pub struct ArchtypeKey {
-    scalar_parts: PartBitmap,
-    vector_parts: PartBitmap,
+    pub(crate) scalar_parts: PartBitmap,
+    pub(crate) vector_parts: PartBitmap,
+    pub(crate) shared_parts: PartBitmap,
+}
+
+impl ArchtypeKey {
+    #[inline]
+    fn parts_mut(&mut self, kind: PartKind) -> &mut PartBitmap {
+        match kind {
+            PartKind::Scalar => &mut self.scalar_parts,
+            PartKind::Vector => &mut self.vector_parts,
+            PartKind::Shared => &mut self.shared_parts,
+        }
+    }
 }
 
+/// How a part is stored for the things in an archtype.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum PartKind {
+    /// One value per row, stored in a chunk column.
+    Scalar,
+    /// A variable length array per row, stored out of line and referenced from a chunk column.
+    Vector,
+    /// One value per chunk, shared by every row in it.
+    Shared,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub struct ArchtypeId(u32);
 
-struct Archtype {
-    key: ArchtypeKey,
-    chunks: Vec<ChunkId>,
+/// Location of a single part's column, the column of change ticks for its rows, and the change
+/// tick for the column as a whole, within the chunks of an archtype.
+pub(crate) struct Column {
+    pub(crate) part: usize,
+    pub(crate) offset: usize,
+    pub(crate) width: usize,
+    pub(crate) ticks: usize,
+    pub(crate) chunk_tick: usize,
+    /// Tracks queries borrowing the column across every chunk of the archtype.
+    pub(crate) borrow: BorrowFlag,
+}
+
+/// Runtime borrow state of a column, holding the number of shared borrows, or `EXCLUSIVE` while
+/// it's borrowed mutably.
+#[derive(Default)]
+pub(crate) struct BorrowFlag(AtomicUsize);
+
+impl BorrowFlag {
+    const EXCLUSIVE: usize = usize::MAX;
+
+    #[inline]
+    pub(crate) fn try_borrow(&self) -> bool {
+        self.0
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |count| {
+                if count < Self::EXCLUSIVE - 1 {
+                    Some(count + 1)
+                } else {
+                    None
+                }
+            })
+            .is_ok()
+    }
+
+    #[inline]
+    pub(crate) fn try_borrow_mut(&self) -> bool {
+        self.0
+            .compare_exchange(0, Self::EXCLUSIVE, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    #[inline]
+    pub(crate) fn release(&self) {
+        self.0.fetch_sub(1, Ordering::Release);
+    }
+
+    #[inline]
+    pub(crate) fn release_mut(&self) {
+        self.0.store(0, Ordering::Release);
+    }
+
+    #[inline]
+    pub(crate) fn is_borrowed_mut(&self) -> bool {
+        self.0.load(Ordering::Relaxed) == Self::EXCLUSIVE
+    }
+}
+
+/// Location of a shared part's value, and the id it was interned under, within the header of each
+/// chunk of an archtype.
+pub(crate) struct SharedColumn {
+    pub(crate) part: usize,
+    pub(crate) offset: usize,
+    pub(crate) width: usize,
+    id: usize,
+}
+
+/// Cached neighbours of an archtype in the add / remove part transition graph. Edges are keyed by
+/// part index, offset by a multiple of `MAX_PART_TYPES` for each `PartKind`.
+#[derive(Copy, Clone, Default)]
+struct ArchtypeEdge {
+    add: Option<ArchtypeId>,
+    remove: Option<ArchtypeId>,
+}
+
+/// Placement of every column within the chunks of an archtype, derived from the width and
+/// alignment the registry records for each part.
+pub(crate) struct ChunkLayout {
+    /// Number of rows which fit in a single chunk.
+    pub(crate) capacity: u32,
+    /// Offset of the column of thing ids.
+    pub(crate) ids: usize,
+    /// Scalar part columns, sorted by part index.
+    pub(crate) columns: Vec<Column>,
+    /// Vector part columns, each holding a `RawVector` per row, sorted by part index.
+    pub(crate) vectors: Vec<Column>,
+    /// Shared part values in the chunk header, sorted by part index.
+    pub(crate) shared: Vec<SharedColumn>,
+}
+
+impl ChunkLayout {
+    fn new(registry: &Registry, key: &ArchtypeKey) -> Self {
+        let mut shared = Vec::new();
+        let mut shared_aligns = Vec::new();
+        for part in key.shared_parts.iter_set_bits() {
+            let Part { align, width, .. } = *registry.part(part);
+            assert!(align <= CHUNK_ALIGN, "part alignment too large");
+            shared.push(SharedColumn {
+                part,
+                offset: 0,
+                width,
+                id: 0,
+            });
+            shared_aligns.push(align);
+        }
+
+        // Tags have no data, so only the archtype's key records them.
+        let mut columns = Vec::new();
+        let mut aligns = Vec::new();
+        for part in key.scalar_parts.iter_set_bits() {
+            let Part { align, width, .. } = *registry.part(part);
+            if width == 0 {
+                continue;
+            }
+            assert!(align <= CHUNK_ALIGN, "part alignment too large");
+            columns.push(Column {
+                part,
+                offset: 0,
+                width,
+                ticks: 0,
+                chunk_tick: 0,
+                borrow: BorrowFlag::default(),
+            });
+            aligns.push(align);
+        }
+
+        let scalars = columns.len();
+        for part in key.vector_parts.iter_set_bits() {
+            columns.push(Column {
+                part,
+                offset: 0,
+                width: size_of::<RawVector>(),
+                ticks: 0,
+                chunk_tick: 0,
+                borrow: BorrowFlag::default(),
+            });
+            aligns.push(align_of::<RawVector>());
+        }
+
+        // Shared values, their ids, and the tick each column was last written at form a header at
+        // the start of the chunk, laid out the same way as the columns below.
+        let mut order = (0..shared.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&i| Reverse(shared_aligns[i]));
+        let split = order
+            .iter()
+            .position(|&i| shared_aligns[i] < align_of::<SharedId>())
+            .unwrap_or(order.len());
+
+        let mut header = 0;
+        for &i in &order[..split] {
+            shared[i].offset = header;
+            header += shared[i].width;
+        }
+        for column in &mut shared {
+            column.id = header;
+            header += size_of::<SharedId>();
+        }
+        for column in &mut columns {
+            column.chunk_tick = header;
+            header += size_of::<u32>();
+        }
+        for &i in &order[split..] {
+            shared[i].offset = header;
+            header += shared[i].width;
+        }
+
+        // The columns must start suitably aligned after the header.
+        let column_align = aligns.iter().copied().fold(align_of::<u32>(), usize::max);
+        let header = (header + column_align - 1) & !(column_align - 1);
+        assert!(
+            header < CHUNK_SIZE_BYTES,
+            "archtype too large to fit in a chunk"
+        );
+
+        // Every row needs a thing id so we can map rows back to things, and a change tick for
+        // each of its parts.
+        let stride = size_of::<ThingId>()
+            + columns
+                .iter()
+                .map(|column| column.width + size_of::<u32>())
+                .sum::<usize>();
+        let capacity = (CHUNK_SIZE_BYTES - header) / stride;
+        assert!(capacity > 0, "archtype too large to fit in a chunk");
+
+        // Sizes are always a multiple of alignment, so laying columns out in order of decreasing
+        // alignment means none of them need padding. The ids and ticks slot in between the parts
+        // aligned to at least a `u32` and those aligned to less.
+        let mut order = (0..columns.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&i| Reverse(aligns[i]));
+        let split = order
+            .iter()
+            .position(|&i| aligns[i] < align_of::<u32>())
+            .unwrap_or(order.len());
+
+        let mut offset = header;
+        for &i in &order[..split] {
+            columns[i].offset = offset;
+            offset += capacity * columns[i].width;
+        }
+
+        let ids = offset;
+        offset += capacity * size_of::<ThingId>();
+        for column in &mut columns {
+            column.ticks = offset;
+            offset += capacity * size_of::<u32>();
+        }
+
+        for &i in &order[split..] {
+            columns[i].offset = offset;
+            offset += capacity * columns[i].width;
+        }
+        debug_assert!(offset <= CHUNK_SIZE_BYTES);
+
+        let vectors = columns.split_off(scalars);
+        Self {
+            capacity: capacity as u32,
+            ids,
+            columns,
+            vectors,
+            shared,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn column(&self, part: usize) -> Option<&Column> {
+        let index = self
+            .columns
+            .binary_search_by_key(&part, |column| column.part)
+            .ok()?;
+        Some(&self.columns[index])
+    }
+
+    #[inline]
+    pub(crate) fn vector_column(&self, part: usize) -> Option<&Column> {
+        let index = self
+            .vectors
+            .binary_search_by_key(&part, |column| column.part)
+            .ok()?;
+        Some(&self.vectors[index])
+    }
+
+    #[inline]
+    pub(crate) fn shared_column(&self, part: usize) -> Option<&SharedColumn> {
+        let index = self
+            .shared
+            .binary_search_by_key(&part, |column| column.part)
+            .ok()?;
+        Some(&self.shared[index])
+    }
+}
+
+pub struct Archtype {
+    pub(crate) key: ArchtypeKey,
+    edges: HashMap<usize, ArchtypeEdge>,
+    pub(crate) chunks: Vec<ChunkId>,
+    pub(crate) layout: ChunkLayout,
+}
+
+impl Archtype {
+    fn new(registry: &Registry, key: ArchtypeKey) -> Self {
+        let layout = ChunkLayout::new(registry, &key);
+        Self {
+            key,
+            edges: HashMap::new(),
+            chunks: Vec::new(),
+            layout,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn column(&self, part: usize) -> Option<&Column> {
+        self.layout.column(part)
+    }
+
+    #[inline]
+    pub(crate) fn vector_column(&self, part: usize) -> Option<&Column> {
+        self.layout.vector_column(part)
+    }
+
+    #[inline]
+    fn has_part(&self, part: usize) -> bool {
+        self.key.scalar_parts.contains(part)
+    }
+
+    #[inline]
+    pub(crate) fn shared_column(&self, part: usize) -> Option<&SharedColumn> {
+        self.layout.shared_column(part)
+    }
+
+    #[inline]
+    fn has_vector_part(&self, part: usize) -> bool {
+        self.key.vector_parts.contains(part)
+    }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
 pub struct ThingId(u32);
 
+impl ThingId {
+    /// Index of the thing's entry in the thing table, which identifies it among live things and
+    /// keys its sparse parts.
+    #[inline]
+    pub(crate) fn slot(self) -> usize {
+        RawTable::<MAX_THINGS>::handle_index(self.0)
+    }
+}
+
 struct Thing {
+    id: ThingId,
     archtype: ArchtypeId,
     chunk: ChunkId,
+    row: u32,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub(crate) struct ChunkId(u32);
+
+/// Index of an interned shared part value. Values are never freed, so equal values always have
+/// the same id.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub(crate) struct SharedId(u32);
+
+struct SharedValue {
+    part: usize,
+    data: Vec<u8>,
+}
+
+// Data goes first so column offsets are relative to an aligned base.
+#[repr(C, align(64))]
+pub struct Chunk {
+    pub(crate) data: [u8; CHUNK_SIZE_BYTES],
+    pub(crate) len: u32,
+}
+
+impl Chunk {
+    #[inline]
+    fn ids_mut(&mut self, layout: &ChunkLayout) -> *mut ThingId {
+        unsafe { self.data.as_mut_ptr().add(layout.ids).cast() }
+    }
+
+    #[inline]
+    fn part_ptr(&self, column: &Column, row: u32) -> *const u8 {
+        debug_assert!(row < self.len);
+        unsafe {
+            self.data
+                .as_ptr()
+                .add(column.offset + column.width * row as usize)
+        }
+    }
+
+    #[inline]
+    fn part_mut_ptr(&mut self, column: &Column, row: u32) -> *mut u8 {
+        debug_assert!(row < self.len);
+        unsafe {
+            self.data
+                .as_mut_ptr()
+                .add(column.offset + column.width * row as usize)
+        }
+    }
+
+    /// Like `part_ptr`, but without borrowing the chunk, for reading a row while queries may be
+    /// writing other columns.
+    #[inline]
+    unsafe fn row_ptr(chunk: *const Chunk, column: &Column, row: u32) -> *const u8 {
+        (chunk as *const u8).add(column.offset + column.width * row as usize)
+    }
+
+    #[inline]
+    fn shared_id(&self, column: &SharedColumn) -> SharedId {
+        unsafe { self.data.as_ptr().add(column.id).cast::<SharedId>().read() }
+    }
+
+    #[inline]
+    fn vector_mut_ptr(&mut self, column: &Column, row: u32) -> *mut RawVector {
+        self.part_mut_ptr(column, row).cast()
+    }
+
+    /// Records that `row` of `column` was written at `tick`, bumping the tick for the column as a
+    /// whole if it's more recent.
+    #[inline]
+    fn stamp(&mut self, column: &Column, row: u32, tick: u32) {
+        unsafe {
+            self.tick_mut_ptr(column, row).write(tick);
+            let column_tick = self.data.as_mut_ptr().add(column.chunk_tick).cast::<u32>();
+            column_tick.write(u32::max(column_tick.read(), tick));
+        }
+    }
+
+    #[inline]
+    fn tick_ptr(&self, column: &Column, row: u32) -> *const u32 {
+        debug_assert!(row < self.len);
+        unsafe {
+            self.data
+                .as_ptr()
+                .add(column.ticks)
+                .cast::<u32>()
+                .add(row as usize)
+        }
+    }
+
+    #[inline]
+    fn tick_mut_ptr(&mut self, column: &Column, row: u32) -> *mut u32 {
+        debug_assert!(row < self.len);
+        unsafe {
+            self.data
+                .as_mut_ptr()
+                .add(column.ticks)
+                .cast::<u32>()
+                .add(row as usize)
+        }
+    }
+}
+
+/// Type erased access to the `SparseVec` holding a sparse part, keyed by thing slot.
+pub(crate) trait SparseStorage {
+    /// # Safety
+    ///
+    /// `data` must hold a valid value of the part, which may be unaligned.
+    unsafe fn insert_raw(&mut self, slot: usize, data: *const u8);
+
+    /// Returns false if the slot was already empty.
+    fn remove_slot(&mut self, slot: usize) -> bool;
+
+    /// The set of slots holding a value.
+    fn occupancy(&self) -> BitSlice<'_>;
+
+    /// Pointer to the value in the first slot, with slots laid out as an array of the part.
+    fn as_ptr(&self) -> *mut u8;
+}
+
+impl<T: Blit, S: Slots<T>> SparseStorage for SparseVec<T, S> {
+    unsafe fn insert_raw(&mut self, slot: usize, data: *const u8) {
+        self.insert_at(slot, data.cast::<T>().read_unaligned());
+    }
+
+    fn remove_slot(&mut self, slot: usize) -> bool {
+        self.remove(slot).is_some()
+    }
+
+    fn occupancy(&self) -> BitSlice<'_> {
+        SparseVec::occupancy(self).as_slice()
+    }
+
+    fn as_ptr(&self) -> *mut u8 {
+        SparseVec::as_ptr(self).cast()
+    }
+}
+
+pub(crate) fn new_sparse_storage<T: Blit + Any>() -> Box<dyn SparseStorage> {
+    Box::new(SparseVec::<T>::new())
+}
+
+/// Storage whose values never move, with a slot reserved for every possible thing.
+pub(crate) fn new_stable_sparse_storage<T: Blit + Any>() -> Box<dyn SparseStorage> {
+    Box::new(StableSparseVec::<T>::with_mapping(MAX_THINGS))
+}
+
+/// The storage for a sparse part, and the queries borrowing it.
+pub(crate) struct SparseSet {
+    storage: NonNull<dyn SparseStorage>,
+    pub(crate) borrow: BorrowFlag,
+}
+
+impl SparseSet {
+    fn new(storage: Box<dyn SparseStorage>) -> Self {
+        Self {
+            storage: NonNull::from(Box::leak(storage)),
+            borrow: BorrowFlag::default(),
+        }
+    }
+
+    /// Returns a pointer to the value in `slot`, if it's occupied. The value may be written
+    /// through the pointer while nothing else borrows it, as for `SparseVec::as_ptr`.
+    ///
+    /// # Safety
+    ///
+    /// `T` must be the type of the part the set was created for.
+    #[inline]
+    pub(crate) unsafe fn get<T>(&self, slot: usize) -> Option<*mut T> {
+        let storage = self.storage.as_ref();
+        if storage.occupancy().contains(slot) {
+            Some(storage.as_ptr().cast::<T>().add(slot))
+        } else {
+            None
+        }
+    }
+
+    /// Pointer to the value in the first slot, as for `SparseStorage::as_ptr`.
+    #[inline]
+    pub(crate) fn as_ptr(&self) -> *mut u8 {
+        unsafe { self.storage.as_ref() }.as_ptr()
+    }
+
+    /// The slots of the things which have the part.
+    #[inline]
+    pub(crate) fn occupancy(&self) -> BitSlice<'_> {
+        unsafe { self.storage.as_ref() }.occupancy()
+    }
+
+    #[inline]
+    fn storage_mut(&mut self) -> &mut dyn SparseStorage {
+        unsafe { self.storage.as_mut() }
+    }
 }
 
-struct ChunkId(u32);
+impl Drop for SparseSet {
+    fn drop(&mut self) {
+        unsafe { drop(Box::from_raw(self.storage.as_ptr())) }
+    }
+}
 
-struct Chunk {
-    len: u32,
-    data: [u8; CHUNK_SIZE_BYTES],
+/// Reserves thing ids without exclusive access to the world, from any number of threads at once.
+/// Reserved ids become live once they're spawned by applying a `CommandBuffer`.
+#[derive(Copy, Clone)]
+pub struct ThingReserver<'world> {
+    table: &'world RawTable<MAX_THINGS>,
 }
 
-pub struct Query {}
+impl<'world> ThingReserver<'world> {
+    #[inline]
+    pub fn reserve(&self) -> ThingId {
+        ThingId(self.table.reserve_handle())
+    }
+}
 
 pub struct World<'registry> {
-    registry: &'registry Registry,
-    thing_cache: RingBuf<ThingId, TABLE_CACHE_SIZE>,
+    pub(crate) registry: &'registry Registry,
     thing_table: RawTable<MAX_THINGS>,
     things: VirtualVec<Thing>,
-    archtype_cache: RingBuf<ArchtypeId, TABLE_CACHE_SIZE>,
     archtype_table: RawTable<MAX_ARCHTYPES>,
-    archtypes: VirtualVec<Archtype>,
-    chunk_cache: RingBuf<ChunkId, TABLE_CACHE_SIZE>,
+    /// The archtype for each combination of parts, so things with the same parts share one.
+    archtype_map: HashMap<ArchtypeKey, ArchtypeId>,
+    pub(crate) archtypes: VirtualVec<Archtype>,
     chunk_table: RawTable<MAX_CHUNKS>,
-    chunks: VirtualVec<Chunk>,
+    pub(crate) chunks: VirtualVec<Chunk>,
+    /// Every shared value ever interned, indexed by `SharedId`. Values are never released, even
+    /// once no chunk refers to them.
+    shared_values: Vec<SharedValue>,
+    /// Resource values, keyed by their index in the registry.
+    resources: HashMap<usize, ResourceData>,
+    /// Storage for each sparse part, keyed by part index.
+    sparse: HashMap<usize, SparseSet>,
+    /// Atomic so queries run by systems on other threads can claim ticks for their writes.
+    tick: AtomicU32,
 }
 
 impl<'registry> World<'registry> {
     pub fn new(registry: &'registry Registry) -> Self {
         Self {
             registry,
-            thing_cache: RingBuf::new(),
             thing_table: RawTable::new(),
             things: VirtualVec::new(MAX_THINGS),
-            archtype_cache: RingBuf::new(),
             archtype_table: RawTable::new(),
+            archtype_map: HashMap::new(),
             archtypes: VirtualVec::new(MAX_ARCHTYPES),
-            chunk_cache: RingBuf::new(),
             chunk_table: RawTable::new(),
             chunks: VirtualVec::new(MAX_CHUNKS),
+            shared_values: Vec::new(),
+            resources: HashMap::new(),
+            sparse: registry
+                .parts()
+                .filter_map(|(index, part)| Some((index, SparseSet::new(part.sparse?()))))
+                .collect(),
+            tick: AtomicU32::new(0),
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    pub fn factory(&mut self) -> Factory<'_, 'registry> {
+        Factory { world: self }
+    }
 
-    #[test]
-    fn create_destroy() {
-        let registry = Registry::new();
-        let world = World::new(&registry);
+    /// Reserves an id for a thing which will be spawned later by applying a `CommandBuffer`. The
+    /// world doesn't contain the thing until then.
+    #[inline]
+    pub fn reserve_thing(&self) -> ThingId {
+        self.thing_reserver().reserve()
+    }
+
+    /// Releases an id from `reserve_thing` which will never be spawned, so it can be reused.
+    /// Returns false if `thing` isn't an unspawned reservation.
+    pub fn cancel_reservation(&mut self, thing: ThingId) -> bool {
+        self.thing_table.cancel_handle(thing.0)
+    }
+
+    /// Returns a handle for reserving thing ids which can be shared between threads.
+    #[inline]
+    pub fn thing_reserver(&self) -> ThingReserver<'_> {
+        ThingReserver {
+            table: &self.thing_table,
+        }
+    }
+
+    /// Removes `thing` and all of its parts from the world, returning false if the id was stale.
+    pub fn destroy(&mut self, thing: ThingId) -> bool {
+        let index = match self.thing_index(thing) {
+            Some(index) => index,
+            None => return false,
+        };
+
+        let Thing {
+            archtype,
+            chunk,
+            row,
+            ..
+        } = self.things[index];
+        self.free_vectors(archtype, chunk, row);
+        self.remove_row(archtype, chunk, row);
+        for set in self.sparse.values_mut() {
+            set.storage_mut().remove_slot(thing.slot());
+        }
+
+        self.things.swap_remove(index);
+        if let Some(moved) = self.things.get(index) {
+            self.thing_table.set(moved.id.0, index as u32);
+        }
+
+        self.thing_table.invalidate(thing.0);
+        self.thing_table.release_handle(thing.0);
+        true
+    }
+
+    /// Adds `part` to `thing`, moving it to a new archtype. If the thing already has a part of
+    /// this type it's overwritten in place. Returns false if the id was stale.
+    pub fn add_part<T: Blit + Any>(&mut self, thing: ThingId, part: T) -> bool {
+        let part_index = self
+            .registry
+            .part_index::<T>()
+            .expect("part type not registered");
+        let part = std::mem::ManuallyDrop::new(part);
+        unsafe { self.add_part_raw(thing, part_index, (&*part as *const T).cast()) }
+    }
+
+    /// Type erased `add_part`, copying the part's bytes from `data`.
+    ///
+    /// # Safety
+    ///
+    /// `data` must be valid for reads of the part's width, and hold a valid value of the part.
+    pub(crate) unsafe fn add_part_raw(
+        &mut self,
+        thing: ThingId,
+        part: usize,
+        data: *const u8,
+    ) -> bool {
+        let index = match self.thing_index(thing) {
+            Some(index) => index,
+            None => return false,
+        };
+        if let Some(set) = self.sparse.get_mut(&part) {
+            set.storage_mut().insert_raw(thing.slot(), data);
+            return true;
+        }
+
+        let Thing {
+            archtype,
+            chunk,
+            row,
+            ..
+        } = self.things[index];
+        let (archtype, chunk, row) = if self.archtype(archtype).has_part(part) {
+            (archtype, chunk, row)
+        } else {
+            let archtype = self.archtype_with_part(archtype, part, PartKind::Scalar);
+            let (chunk, row) = self.migrate(index, archtype, None);
+            (archtype, chunk, row)
+        };
+
+        let archtype_index = self.archtype_index(archtype);
+        let column = match self.archtypes[archtype_index].column(part) {
+            Some(column) => column,
+            // Tags have nothing to write.
+            None => return true,
+        };
+        let tick = self.next_tick();
+        let chunk_index = self.chunk_index(chunk);
+        let chunk_data = &mut self.chunks[chunk_index];
+        std::ptr::copy_nonoverlapping(data, chunk_data.part_mut_ptr(column, row), column.width);
+        chunk_data.stamp(column, row, tick);
+        true
+    }
+
+    /// Removes the part of type `T` from `thing`, moving it to a new archtype. Returns the removed
+    /// part, or `None` if the id was stale or the thing didn't have the part.
+    pub fn remove_part<T: Blit + Any>(&mut self, thing: ThingId) -> Option<T> {
+        let part_index = self.registry.part_index::<T>()?;
+        let part = unsafe { (&*self.get::<T>(thing)? as *const T).read() };
+        self.remove_part_raw(thing, part_index);
+        Some(part)
+    }
+
+    /// Type erased `remove_part`, discarding the removed part. Returns false if the id was stale
+    /// or the thing didn't have the part.
+    pub(crate) fn remove_part_raw(&mut self, thing: ThingId, part: usize) -> bool {
+        let index = match self.thing_index(thing) {
+            Some(index) => index,
+            None => return false,
+        };
+        if let Some(set) = self.sparse.get_mut(&part) {
+            return set.storage_mut().remove_slot(thing.slot());
+        }
+
+        let archtype = self.things[index].archtype;
+        if !self.archtype(archtype).has_part(part) {
+            return false;
+        }
+
+        let archtype = self.archtype_without_part(archtype, part, PartKind::Scalar);
+        self.migrate(index, archtype, None);
+        true
+    }
+
+    /// Appends `value` to the vector part of type `T` on `thing`, first adding an empty vector
+    /// part if the thing doesn't have one. Returns false if the id was stale.
+    pub fn push_vector_part<T: Blit + Any>(&mut self, thing: ThingId, value: T) -> bool {
+        let part_index = self
+            .registry
+            .part_index::<T>()
+            .expect("part type not registered");
+        let index = match self.thing_index(thing) {
+            Some(index) => index,
+            None => return false,
+        };
+
+        let archtype = self.things[index].archtype;
+        if !self.archtype(archtype).has_vector_part(part_index) {
+            let archtype = self.archtype_with_part(archtype, part_index, PartKind::Vector);
+            let (chunk, row) = self.migrate(index, archtype, None);
+            let archtype_index = self.archtype_index(archtype);
+            let chunk_index = self.chunk_index(chunk);
+            let column = self.archtypes[archtype_index]
+                .vector_column(part_index)
+                .unwrap();
+            let chunk_data = &mut self.chunks[chunk_index];
+            unsafe {
+                chunk_data
+                    .vector_mut_ptr(column, row)
+                    .write(RawVector::EMPTY)
+            }
+        }
+
+        let vector = self.vector_mut(index, part_index).unwrap();
+        let value = std::mem::ManuallyDrop::new(value);
+        unsafe {
+            (*vector).extend(
+                align_of::<T>(),
+                size_of::<T>(),
+                (&*value as *const T).cast(),
+                1,
+            )
+        }
+        true
+    }
+
+    /// Removes the last element from the vector part of type `T` on `thing`. Returns `None` if
+    /// the id was stale, or the thing has no such vector part or it's empty.
+    pub fn pop_vector_part<T: Blit + Any>(&mut self, thing: ThingId) -> Option<T> {
+        let part_index = self.registry.part_index::<T>()?;
+        let index = self.thing_index(thing)?;
+        let vector = self.vector_mut(index, part_index)?;
+        let mut value = MaybeUninit::<T>::uninit();
+        unsafe {
+            if (*vector).pop(size_of::<T>(), value.as_mut_ptr().cast()) {
+                Some(value.assume_init())
+            } else {
+                None
+            }
+        }
+    }
+
+    /// Removes the vector part of type `T` from `thing`, moving it to a new archtype. Returns the
+    /// removed elements, or `None` if the id was stale or the thing didn't have the vector part.
+    pub fn remove_vector_part<T: Blit + Any>(&mut self, thing: ThingId) -> Option<Vec<T>> {
+        let part_index = self.registry.part_index::<T>()?;
+        let index = self.thing_index(thing)?;
+
+        let archtype = self.things[index].archtype;
+        if !self.archtype(archtype).has_vector_part(part_index) {
+            return None;
+        }
+
+        let values = self.get_vector_part::<T>(thing)?;
+        let mut vec = Vec::with_capacity(values.len());
+        unsafe {
+            std::ptr::copy_nonoverlapping(values.as_ptr(), vec.as_mut_ptr(), values.len());
+            vec.set_len(values.len());
+        }
+        drop(values);
+
+        let archtype = self.archtype_without_part(archtype, part_index, PartKind::Vector);
+        self.migrate(index, archtype, None);
+        Some(vec)
+    }
+
+    /// Borrows the elements of the vector part of type `T` on `thing`, stopping queries from
+    /// writing the part until the returned guard is dropped.
+    ///
+    /// Panics if a query is writing the part.
+    pub fn get_vector_part<T: Blit + Any>(&self, thing: ThingId) -> Option<PartRef<'_, [T]>> {
+        let part = self.registry.part_index::<T>()?;
+        let thing = self.thing(thing)?;
+        let column = self.archtype(thing.archtype).vector_column(part)?;
+        let borrow = borrow_part::<T>(&column.borrow);
+        unsafe {
+            let vector = Chunk::row_ptr(self.chunk_ptr(thing.chunk), column, thing.row);
+            let values = (*vector.cast::<RawVector>()).as_slice::<T>();
+            Some(PartRef::new(values, Some(borrow)))
+        }
+    }
+
+    pub fn get_vector_part_mut<T: Blit + Any>(&mut self, thing: ThingId) -> Option<&mut [T]> {
+        let part = self.registry.part_index::<T>()?;
+        let index = self.thing_index(thing)?;
+        let vector = self.vector_mut(index, part)?;
+        unsafe { Some((*vector).as_mut_slice()) }
+    }
+
+    /// Sets the shared part of type `T` on `thing` to `value`, moving it to a chunk whose things
+    /// all share that value. Returns false if the id was stale.
+    ///
+    /// Shared parts have no change ticks, so this doesn't count as a write for `Changed` filters.
+    ///
+    /// Chunks are never freed, so a chunk emptied by moving its things to another shared value
+    /// stays allocated, and is only reused by things which get its shared values back.
+    pub fn add_shared_part<T: Blit + Any + PartialEq>(&mut self, thing: ThingId, value: T) -> bool {
+        let part_index = self
+            .registry
+            .part_index::<T>()
+            .expect("part type not registered");
+        let index = match self.thing_index(thing) {
+            Some(index) => index,
+            None => return false,
+        };
+
+        let id = self.intern_shared(part_index, &value);
+        let Thing {
+            archtype, chunk, ..
+        } = self.things[index];
+        let target = match self.archtype(archtype).shared_column(part_index) {
+            Some(column) if self.chunk(chunk).shared_id(column) == id => return true,
+            Some(_) => archtype,
+            None => self.archtype_with_part(archtype, part_index, PartKind::Shared),
+        };
+        self.migrate(index, target, Some((part_index, id)));
+        true
+    }
+
+    /// Removes the shared part of type `T` from `thing`, moving it to a new archtype. Returns the
+    /// removed value, or `None` if the id was stale or the thing didn't have the shared part.
+    pub fn remove_shared_part<T: Blit + Any>(&mut self, thing: ThingId) -> Option<T> {
+        let part_index = self.registry.part_index::<T>()?;
+        let index = self.thing_index(thing)?;
+
+        let value = unsafe { (self.get_shared_part::<T>(thing)? as *const T).read() };
+        let archtype = self.things[index].archtype;
+        let archtype = self.archtype_without_part(archtype, part_index, PartKind::Shared);
+        self.migrate(index, archtype, None);
+        Some(value)
+    }
+
+    pub fn get_shared_part<T: Blit + Any>(&self, thing: ThingId) -> Option<&T> {
+        let part = self.registry.part_index::<T>()?;
+        let thing = self.thing(thing)?;
+        let column = self.archtype(thing.archtype).shared_column(part)?;
+        // Queries may be writing other columns of the chunk, so it can't be borrowed as a whole.
+        let chunk = self.chunk_ptr(thing.chunk);
+        unsafe { Some(&*(chunk as *const u8).add(column.offset).cast::<T>()) }
+    }
+
+    /// Plays back the structural changes recorded in `commands`, in order, leaving it empty.
+    pub fn apply(&mut self, commands: &mut CommandBuffer) {
+        commands.apply(self);
+    }
+
+    /// Throws away the changes recorded in `commands` without playing them back, releasing the
+    /// ids reserved for things it would have spawned.
+    pub fn discard(&mut self, commands: &mut CommandBuffer) {
+        commands.discard(self);
+    }
+
+    /// Stores `value` as the world's resource of type `T`, returning the previous value.
+    pub fn insert_resource<T: Blit + Any>(&mut self, value: T) -> Option<T> {
+        let index = registered_resource::<T>(self.registry);
+        match self.resources.entry(index) {
+            Entry::Occupied(entry) => unsafe {
+                Some(entry.get().data.cast::<T>().as_ptr().replace(value))
+            },
+            Entry::Vacant(entry) => {
+                let data = ResourceData::new(self.registry.part(index));
+                unsafe { data.data.cast::<T>().as_ptr().write(value) };
+                entry.insert(data);
+                None
+            }
+        }
+    }
+
+    pub fn remove_resource<T: Blit + Any>(&mut self) -> Option<T> {
+        let index = registered_resource::<T>(self.registry);
+        let data = self.resources.remove(&index)?;
+        unsafe { Some(data.data.cast::<T>().as_ptr().read()) }
+    }
+
+    /// Borrows the resource of type `T`, if the world has one. Panics if it's already borrowed
+    /// mutably.
+    pub fn resource<T: Blit + Any>(&self) -> Option<Resource<'_, T>> {
+        let index = registered_resource::<T>(self.registry);
+        let data = self.resources.get(&index)?;
+        if !data.borrow.try_borrow() {
+            resource_borrowed::<T>(true)
+        }
+        Some(Resource {
+            data,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Borrows the resource of type `T` mutably, if the world has one. Panics if it's already
+    /// borrowed.
+    pub fn resource_mut<T: Blit + Any>(&self) -> Option<ResourceMut<'_, T>> {
+        let index = registered_resource::<T>(self.registry);
+        let data = self.resources.get(&index)?;
+        if !data.borrow.try_borrow_mut() {
+            resource_borrowed::<T>(data.borrow.is_borrowed_mut())
+        }
+        Some(ResourceMut {
+            data,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Creates a query over every thing which has all the parts fetched by `Q`.
+    ///
+    /// Any number of queries may be alive at once, but panics if the query would write a part
+    /// that another query is borrowing, or read a part another query is writing.
+    pub fn query<Q: Fetch>(&self) -> Query<'_, 'registry, Q> {
+        Query::new(self)
+    }
+
+    /// Creates a query over every thing which has all the parts fetched by `Q`, and which passes
+    /// the filter `F`. Panics on conflicting borrows, as for `query`.
+    pub fn query_filtered<Q: Fetch, F: Filter>(&self) -> Query<'_, 'registry, Q, F> {
+        Query::new(self)
+    }
+
+    /// Returns the tick stamped on the most recent write to any part in the world. Every write
+    /// advances the tick, so parts written after this point will pass a `Changed` filter for the
+    /// returned tick, and chunks with no such writes can be skipped without looking at their rows.
+    #[inline]
+    pub fn tick(&self) -> u32 {
+        self.tick.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub(crate) fn next_tick(&self) -> u32 {
+        self.tick.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Returns the storage of the part `part`, if it's sparse.
+    #[inline]
+    pub(crate) fn sparse_set(&self, part: usize) -> Option<&SparseSet> {
+        self.sparse.get(&part)
+    }
+
+    /// Returns the archtype index, chunk and row of the live thing in `slot`.
+    pub(crate) fn locate_slot(&self, slot: usize) -> Option<(usize, ChunkId, usize)> {
+        let thing = &self.things[self.thing_table.get_by_index(slot)? as usize];
+        Some((
+            self.archtype_index(thing.archtype),
+            thing.chunk,
+            thing.row as usize,
+        ))
+    }
+
+    #[inline]
+    pub fn contains(&self, thing: ThingId) -> bool {
+        self.thing_index(thing).is_some()
+    }
+
+    /// Borrows the part of type `T` on `thing`, stopping queries from writing the part until the
+    /// returned guard is dropped.
+    ///
+    /// Panics if a query is writing the part.
+    pub fn get<T: Blit + Any>(&self, thing: ThingId) -> Option<PartRef<'_, T>> {
+        let part = self.registry.part_index::<T>()?;
+        if let Some(set) = self.sparse.get(&part) {
+            self.thing_index(thing)?;
+            let value = unsafe { set.get::<T>(thing.slot())? };
+            return Some(PartRef::new(value, Some(borrow_part::<T>(&set.borrow))));
+        }
+        let thing = self.thing(thing)?;
+        let archtype = self.archtype(thing.archtype);
+        let column = match archtype.column(part) {
+            Some(column) => column,
+            None => {
+                return archtype
+                    .has_part(part)
+                    .then(|| PartRef::new(tag::<T>(), None))
+            }
+        };
+        let borrow = borrow_part::<T>(&column.borrow);
+        let value = unsafe { Chunk::row_ptr(self.chunk_ptr(thing.chunk), column, thing.row) };
+        Some(PartRef::new(value.cast(), Some(borrow)))
+    }
+
+    pub fn get_mut<T: Blit + Any>(&mut self, thing: ThingId) -> Option<&mut T> {
+        let part = self.registry.part_index::<T>()?;
+        let index = self.thing_index(thing)?;
+        if let Some(set) = self.sparse.get(&part) {
+            return unsafe { set.get::<T>(thing.slot()).map(|value| &mut *value) };
+        }
+        let Thing {
+            archtype,
+            chunk,
+            row,
+            ..
+        } = self.things[index];
+        let archtype = &self.archtypes[self.archtype_index(archtype)];
+        let column = match archtype.column(part) {
+            Some(column) => column,
+            None => return archtype.has_part(part).then(tag::<T>),
+        };
+        let tick = self.next_tick();
+        let chunk_index = self.chunk_index(chunk);
+        let chunk = &mut self.chunks[chunk_index];
+        chunk.stamp(column, row, tick);
+        unsafe { Some(&mut *chunk.part_mut_ptr(column, row).cast::<T>()) }
+    }
+
+    /// Returns the index of `thing` in `things`, or `None` if the id is stale or only reserved.
+    #[inline]
+    fn thing_index(&self, thing: ThingId) -> Option<usize> {
+        Some(self.thing_table.get_if_set(thing.0)? as usize)
+    }
+
+    #[inline]
+    fn thing(&self, thing: ThingId) -> Option<&Thing> {
+        Some(&self.things[self.thing_index(thing)?])
+    }
+
+    #[inline]
+    fn archtype_index(&self, archtype: ArchtypeId) -> usize {
+        self.archtype_table
+            .get(archtype.0)
+            .expect("invalid archtype id") as usize
+    }
+
+    #[inline]
+    fn archtype(&self, archtype: ArchtypeId) -> &Archtype {
+        &self.archtypes[self.archtype_index(archtype)]
+    }
+
+    #[inline]
+    pub(crate) fn chunk_index(&self, chunk: ChunkId) -> usize {
+        self.chunk_table.get(chunk.0).expect("invalid chunk id") as usize
+    }
+
+    #[inline]
+    fn chunk(&self, chunk: ChunkId) -> &Chunk {
+        &self.chunks[self.chunk_index(chunk)]
+    }
+
+    /// Returns a pointer to `chunk`, for reading through `&self` while queries may be writing
+    /// some of its columns.
+    #[inline]
+    fn chunk_ptr(&self, chunk: ChunkId) -> *const Chunk {
+        unsafe { self.chunks.as_ptr().add(self.chunk_index(chunk)) }
+    }
+
+    fn find_or_create_archtype(&mut self, key: &ArchtypeKey) -> ArchtypeId {
+        if let Some(&archtype) = self.archtype_map.get(key) {
+            return archtype;
+        }
+
+        let id = ArchtypeId(self.archtype_table.allocate_handle());
+        let index = self.archtypes.len();
+        self.archtype_table.set(id.0, index as u32);
+        self.archtypes
+            .push(Archtype::new(self.registry, key.clone()));
+        self.archtype_map.insert(key.clone(), id);
+        id
+    }
+
+    /// Returns the id of the shared value of `part` equal to `value`, interning it if this is the
+    /// first time we've seen it.
+    fn intern_shared<T: Blit + Any + PartialEq>(&mut self, part: usize, value: &T) -> SharedId {
+        // The number of distinct shared values is expected to be small, so a linear scan will do.
+        let existing = self.shared_values.iter().position(|shared| {
+            shared.part == part
+                && unsafe { shared.data.as_ptr().cast::<T>().read_unaligned() } == *value
+        });
+        if let Some(index) = existing {
+            return SharedId(index as u32);
+        }
+
+        let mut data = vec![0; size_of::<T>()];
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                (value as *const T).cast::<u8>(),
+                data.as_mut_ptr(),
+                data.len(),
+            )
+        }
+        self.shared_values.push(SharedValue { part, data });
+        SharedId(self.shared_values.len() as u32 - 1)
+    }
+
+    /// Returns a chunk belonging to `archtype` with the shared values `shared`, one per shared
+    /// part in order, and room for at least one more row. Allocates a new chunk if all the
+    /// existing ones are full.
+    fn find_or_create_chunk(&mut self, archtype: ArchtypeId, shared: &[SharedId]) -> ChunkId {
+        let archtype_index = self.archtype_index(archtype);
+        let Archtype { chunks, layout, .. } = &self.archtypes[archtype_index];
+        debug_assert_eq!(layout.shared.len(), shared.len());
+        for &chunk in chunks {
+            let chunk_data = self.chunk(chunk);
+            if chunk_data.len < layout.capacity
+                && layout
+                    .shared
+                    .iter()
+                    .map(|column| chunk_data.shared_id(column))
+                    .eq(shared.iter().copied())
+            {
+                return chunk;
+            }
+        }
+
+        let id = ChunkId(self.chunk_table.allocate_handle());
+        let index = self.chunks.len();
+        self.chunk_table.set(id.0, index as u32);
+        self.chunks.push(Chunk {
+            data: [0; CHUNK_SIZE_BYTES],
+            len: 0,
+        });
+
+        let chunk_data = &mut self.chunks[index];
+        for (column, &shared_id) in layout.shared.iter().zip(shared) {
+            let value = &self.shared_values[shared_id.0 as usize];
+            debug_assert_eq!(value.part, column.part);
+            chunk_data.data[column.offset..column.offset + column.width]
+                .copy_from_slice(&value.data);
+            unsafe {
+                chunk_data
+                    .data
+                    .as_mut_ptr()
+                    .add(column.id)
+                    .cast::<SharedId>()
+                    .write(shared_id)
+            }
+        }
+        self.archtypes[archtype_index].chunks.push(id);
+        id
+    }
+
+    /// Appends a row for `id` to a chunk of `archtype` with room for it and the shared values
+    /// `shared`, returning the chunk and row. The row's parts are left for the caller to fill in.
+    fn push_row(
+        &mut self,
+        archtype: ArchtypeId,
+        id: ThingId,
+        shared: &[SharedId],
+    ) -> (ChunkId, u32) {
+        let chunk = self.find_or_create_chunk(archtype, shared);
+        let archtype_index = self.archtype_index(archtype);
+        let chunk_index = self.chunk_index(chunk);
+        let layout = &self.archtypes[archtype_index].layout;
+        let chunk_data = &mut self.chunks[chunk_index];
+        let row = chunk_data.len;
+        chunk_data.len += 1;
+        unsafe { chunk_data.ids_mut(layout).add(row as usize).write(id) }
+        (chunk, row)
+    }
+
+    /// Creates a new thing in the archtype matching `key`, copying each part's bytes out of
+    /// `data` at the given offsets. Vector parts are given as an offset and element count, and
+    /// shared parts as interned ids in part order. The thing takes the id `reserved` if given,
+    /// which must have come from `reserve_thing`.
+    pub(crate) fn spawn(
+        &mut self,
+        reserved: Option<ThingId>,
+        key: &ArchtypeKey,
+        parts: &[(usize, usize)],
+        vectors: &[(usize, usize, usize)],
+        shared: &[SharedId],
+        data: &[u8],
+    ) -> ThingId {
+        // Sparse parts live outside chunks, so they don't count towards the archtype.
+        let archtype = if parts.iter().any(|(part, _)| self.sparse.contains_key(part)) {
+            let mut key = key.clone();
+            for (part, _) in parts {
+                if self.sparse.contains_key(part) {
+                    key.scalar_parts.clear(*part);
+                }
+            }
+            self.find_or_create_archtype(&key)
+        } else {
+            self.find_or_create_archtype(key)
+        };
+
+        let id = match reserved {
+            Some(id) => {
+                assert!(
+                    self.thing_table.is_unset(id.0),
+                    "spawned thing id was not reserved"
+                );
+                id
+            }
+            None => ThingId(self.thing_table.allocate_handle()),
+        };
+        let index = self.things.len();
+        self.thing_table.set(id.0, index as u32);
+
+        let (chunk, row) = self.push_row(archtype, id, shared);
+        let tick = self.next_tick();
+        let archtype_index = self.archtype_index(archtype);
+        let chunk_index = self.chunk_index(chunk);
+        let archtype_data = &self.archtypes[archtype_index];
+        let chunk_data = &mut self.chunks[chunk_index];
+        for &(part, offset) in parts {
+            // Tags and sparse parts have no column.
+            let column = match archtype_data.column(part) {
+                Some(column) => column,
+                None => continue,
+            };
+            unsafe {
+                std::ptr::copy_nonoverlapping(
+                    data.as_ptr().add(offset),
+                    chunk_data.part_mut_ptr(column, row),
+                    column.width,
+                );
+            }
+            chunk_data.stamp(column, row, tick);
+        }
+        for &(part, offset, count) in vectors {
+            let column = archtype_data.vector_column(part).unwrap();
+            let Part { align, width, .. } = *self.registry.part(part);
+            unsafe {
+                let vector = chunk_data.vector_mut_ptr(column, row);
+                vector.write(RawVector::EMPTY);
+                (*vector).extend(align, width, data.as_ptr().add(offset), count);
+            }
+            chunk_data.stamp(column, row, tick);
+        }
+
+        self.things.push(Thing {
+            id,
+            archtype,
+            chunk,
+            row,
+        });
+        for &(part, offset) in parts {
+            if let Some(set) = self.sparse.get_mut(&part) {
+                unsafe {
+                    set.storage_mut()
+                        .insert_raw(id.slot(), data.as_ptr().add(offset))
+                }
+            }
+        }
+
+        id
+    }
+
+    /// Returns the archtype reached by adding `part` to `archtype`, following the cached edge if
+    /// we've made this transition before.
+    fn archtype_with_part(
+        &mut self,
+        archtype: ArchtypeId,
+        part: usize,
+        kind: PartKind,
+    ) -> ArchtypeId {
+        let index = self.archtype_index(archtype);
+        let edge = part + kind as usize * MAX_PART_TYPES;
+        if let Some(target) = self.archtypes[index].edges.get(&edge).and_then(|e| e.add) {
+            return target;
+        }
+
+        let mut key = self.archtypes[index].key.clone();
+        key.parts_mut(kind).set(part);
+        let target = self.find_or_create_archtype(&key);
+        self.link_archtypes(archtype, target, edge);
+        target
+    }
+
+    /// Returns the archtype reached by removing `part` from `archtype`, following the cached edge
+    /// if we've made this transition before.
+    fn archtype_without_part(
+        &mut self,
+        archtype: ArchtypeId,
+        part: usize,
+        kind: PartKind,
+    ) -> ArchtypeId {
+        let index = self.archtype_index(archtype);
+        let edge = part + kind as usize * MAX_PART_TYPES;
+        if let Some(target) = self.archtypes[index]
+            .edges
+            .get(&edge)
+            .and_then(|e| e.remove)
+        {
+            return target;
+        }
+
+        let mut key = self.archtypes[index].key.clone();
+        key.parts_mut(kind).clear(part);
+        let target = self.find_or_create_archtype(&key);
+        self.link_archtypes(target, archtype, edge);
+        target
+    }
+
+    /// Records the transition edges in both directions between two archtypes that differ only
+    /// by the part identified by `edge`.
+    fn link_archtypes(&mut self, without: ArchtypeId, with: ArchtypeId, edge: usize) {
+        let without_index = self.archtype_index(without);
+        let with_index = self.archtype_index(with);
+        self.archtypes[without_index]
+            .edges
+            .entry(edge)
+            .or_default()
+            .add = Some(with);
+        self.archtypes[with_index]
+            .edges
+            .entry(edge)
+            .or_default()
+            .remove = Some(without);
+    }
+
+    /// Moves the thing at `index` into `new_archtype`, carrying over every part the two archtypes
+    /// have in common and freeing any vector parts the new archtype lacks. Parts only present in
+    /// the new archtype are left for the caller to initialize, except for a shared part which
+    /// must be given as `shared`, and which also overrides the thing's current value.
+    fn migrate(
+        &mut self,
+        index: usize,
+        new_archtype: ArchtypeId,
+        shared: Option<(usize, SharedId)>,
+    ) -> (ChunkId, u32) {
+        let Thing {
+            id,
+            archtype: old_archtype,
+            chunk: old_chunk,
+            row: old_row,
+        } = self.things[index];
+
+        let old_chunk_data = self.chunk(old_chunk);
+        let old_archtype_data = self.archtype(old_archtype);
+        let new_shared = self
+            .archtype(new_archtype)
+            .layout
+            .shared
+            .iter()
+            .map(|new_column| match shared {
+                Some((part, id)) if part == new_column.part => id,
+                _ => old_chunk_data.shared_id(
+                    old_archtype_data
+                        .shared_column(new_column.part)
+                        .expect("shared part not initialized"),
+                ),
+            })
+            .collect::<Vec<_>>();
+        let (new_chunk, new_row) = self.push_row(new_archtype, id, &new_shared);
+
+        let old_archtype_index = self.archtype_index(old_archtype);
+        let new_archtype_index = self.archtype_index(new_archtype);
+        let old_chunk_index = self.chunk_index(old_chunk);
+        let new_chunk_index = self.chunk_index(new_chunk);
+        // Either the archtypes differ, or a shared part's value changed so the shared values of
+        // the two chunks differ.
+        debug_assert!(
+            old_chunk_index != new_chunk_index,
+            "thing migrated to its own chunk"
+        );
+
+        let old_layout = &self.archtypes[old_archtype_index].layout;
+        let new_layout = &self.archtypes[new_archtype_index].layout;
+        let chunks = self.chunks.as_mut_ptr();
+        let common_columns = new_layout
+            .columns
+            .iter()
+            .filter_map(|new| Some((old_layout.column(new.part)?, new)));
+        let common_vectors = new_layout
+            .vectors
+            .iter()
+            .filter_map(|new| Some((old_layout.vector_column(new.part)?, new)));
+        for (old_column, new_column) in common_columns.chain(common_vectors) {
+            // SAFETY: parts are `Blit`, and the two chunks are always distinct since they belong
+            // to different archtypes or have different shared values.
+            unsafe {
+                let old_chunk_data = &*chunks.add(old_chunk_index);
+                let new_chunk_data = &mut *chunks.add(new_chunk_index);
+                std::ptr::copy_nonoverlapping(
+                    old_chunk_data.part_ptr(old_column, old_row),
+                    new_chunk_data.part_mut_ptr(new_column, new_row),
+                    new_column.width,
+                );
+                new_chunk_data.stamp(
+                    new_column,
+                    new_row,
+                    old_chunk_data.tick_ptr(old_column, old_row).read(),
+                );
+            }
+        }
+
+        for old_column in &old_layout.vectors {
+            if new_layout.vector_column(old_column.part).is_none() {
+                let Part { align, width, .. } = *self.registry.part(old_column.part);
+                unsafe {
+                    let old_chunk_data = &mut *chunks.add(old_chunk_index);
+                    (*old_chunk_data.vector_mut_ptr(old_column, old_row)).free(align, width);
+                }
+            }
+        }
+
+        self.remove_row(old_archtype, old_chunk, old_row);
+        self.things[index] = Thing {
+            id,
+            archtype: new_archtype,
+            chunk: new_chunk,
+            row: new_row,
+        };
+
+        (new_chunk, new_row)
+    }
+
+    /// Removes `row` from `chunk`, moving the chunk's last row into the hole so the chunk stays
+    /// dense. Any vector parts of the removed row must already have been freed or moved.
+    fn remove_row(&mut self, archtype: ArchtypeId, chunk: ChunkId, row: u32) {
+        let archtype_index = self.archtype_index(archtype);
+        let chunk_index = self.chunk_index(chunk);
+        let layout = &self.archtypes[archtype_index].layout;
+        let chunk_data = &mut self.chunks[chunk_index];
+
+        let last = chunk_data.len - 1;
+        if row != last {
+            let moved = unsafe {
+                let ids = chunk_data.ids_mut(layout);
+                let moved = ids.add(last as usize).read();
+                ids.add(row as usize).write(moved);
+                for column in layout.columns.iter().chain(&layout.vectors) {
+                    std::ptr::copy_nonoverlapping(
+                        chunk_data.part_ptr(column, last),
+                        chunk_data.part_mut_ptr(column, row),
+                        column.width,
+                    );
+                    let tick = chunk_data.tick_ptr(column, last).read();
+                    chunk_data.tick_mut_ptr(column, row).write(tick);
+                }
+                moved
+            };
+
+            let moved_index = self
+                .thing_table
+                .get(moved.0)
+                .expect("chunk contains invalid thing id");
+            self.things[moved_index as usize].row = row;
+        }
+        chunk_data.len = last;
+    }
+
+    /// Releases the storage of every vector part in `row`.
+    fn free_vectors(&mut self, archtype: ArchtypeId, chunk: ChunkId, row: u32) {
+        let archtype_index = self.archtype_index(archtype);
+        let chunk_index = self.chunk_index(chunk);
+        let layout = &self.archtypes[archtype_index].layout;
+        let chunk_data = &mut self.chunks[chunk_index];
+        for column in &layout.vectors {
+            let Part { align, width, .. } = *self.registry.part(column.part);
+            unsafe { (*chunk_data.vector_mut_ptr(column, row)).free(align, width) }
+        }
+    }
+
+    /// Returns the vector part `part` of the thing at `index`, stamping it as changed. The
+    /// pointer is only valid until the next structural change to the world.
+    fn vector_mut(&mut self, index: usize, part: usize) -> Option<*mut RawVector> {
+        let Thing {
+            archtype,
+            chunk,
+            row,
+            ..
+        } = self.things[index];
+        let tick = self.next_tick();
+        let column = self.archtypes[self.archtype_index(archtype)].vector_column(part)?;
+        let chunk_index = self.chunk_index(chunk);
+        let chunk_data = &mut self.chunks[chunk_index];
+        chunk_data.stamp(column, row, tick);
+        Some(chunk_data.vector_mut_ptr(column, row))
+    }
+}
+
+/// Returns a reference to the value of the tag `T`, which needs no storage.
+#[inline]
+fn tag<'a, T>() -> &'a mut T {
+    assert_eq!(size_of::<T>(), 0);
+    unsafe { &mut *NonNull::dangling().as_ptr() }
+}
+
+/// Borrows `flag`, which tracks the storage of the part `T`, for a `PartRef`. Panics if a query
+/// is writing the part.
+#[inline]
+fn borrow_part<T>(flag: &BorrowFlag) -> &BorrowFlag {
+    if !flag.try_borrow() {
+        borrowed_mut::<T>()
+    }
+    flag
+}
+
+#[cold]
+#[inline(never)]
+fn borrowed_mut<T>() {
+    panic!(
+        "part `{}` is already borrowed mutably by a query",
+        type_name::<T>()
+    );
+}
+
+/// Type erased `World::intern_shared`, for interning values recorded as raw bytes.
+///
+/// # Safety
+///
+/// `data` must be valid for reads of a `T`, and `part` must be the part index of `T`.
+pub(crate) unsafe fn intern_shared_raw<T: Blit + Any + PartialEq>(
+    world: &mut World,
+    part: usize,
+    data: *const u8,
+) -> SharedId {
+    let value = data.cast::<T>().read_unaligned();
+    world.intern_shared(part, &value)
+}
+
+impl<'registry> Drop for World<'registry> {
+    fn drop(&mut self) {
+        for archtype in self.archtypes.iter() {
+            let layout = &archtype.layout;
+            if layout.vectors.is_empty() {
+                continue;
+            }
+            for &chunk in &archtype.chunks {
+                let chunk_index = self.chunk_table.get(chunk.0).unwrap() as usize;
+                let chunk_data = &mut self.chunks[chunk_index];
+                for column in &layout.vectors {
+                    let Part { align, width, .. } = *self.registry.part(column.part);
+                    for row in 0..chunk_data.len {
+                        unsafe { (*chunk_data.vector_mut_ptr(column, row)).free(align, width) }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn registered_resource<T: Any>(registry: &Registry) -> usize {
+    registry
+        .resource_index::<T>()
+        .expect("resource type not registered")
+}
+
+#[cold]
+#[inline(never)]
+fn resource_borrowed<T>(mutably: bool) {
+    if mutably {
+        panic!(
+            "resource `{}` is already borrowed mutably",
+            type_name::<T>()
+        );
+    } else {
+        panic!("resource `{}` is already borrowed", type_name::<T>());
+    }
+}
+
+/// A resource's value, allocated with the width and alignment the registry records for it.
+struct ResourceData {
+    data: NonNull<u8>,
+    layout: Layout,
+    borrow: BorrowFlag,
+}
+
+impl ResourceData {
+    fn new(part: &Part) -> Self {
+        let layout = Layout::from_size_align(part.width, part.align).unwrap();
+        let data = if layout.size() == 0 {
+            // Zero sized values need no storage, only an aligned pointer.
+            NonNull::new(layout.align() as *mut u8).unwrap()
+        } else {
+            let data = unsafe { std::alloc::alloc(layout) };
+            NonNull::new(data).unwrap_or_else(|| std::alloc::handle_alloc_error(layout))
+        };
+        Self {
+            data,
+            layout,
+            borrow: BorrowFlag::default(),
+        }
+    }
+}
+
+impl Drop for ResourceData {
+    fn drop(&mut self) {
+        if self.layout.size() != 0 {
+            unsafe { std::alloc::dealloc(self.data.as_ptr(), self.layout) }
+        }
+    }
+}
+
+/// A shared borrow of a world resource, released when dropped.
+pub struct Resource<'world, T> {
+    data: &'world ResourceData,
+    _marker: PhantomData<&'world T>,
+}
+
+impl<'world, T> Deref for Resource<'world, T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe { &*self.data.data.cast::<T>().as_ptr() }
+    }
+}
+
+impl<'world, T> Drop for Resource<'world, T> {
+    fn drop(&mut self) {
+        self.data.borrow.release();
+    }
+}
+
+/// An exclusive borrow of a world resource, released when dropped.
+pub struct ResourceMut<'world, T> {
+    data: &'world ResourceData,
+    _marker: PhantomData<&'world mut T>,
+}
+
+impl<'world, T> Deref for ResourceMut<'world, T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe { &*self.data.data.cast::<T>().as_ptr() }
+    }
+}
+
+impl<'world, T> DerefMut for ResourceMut<'world, T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data.data.cast::<T>().as_ptr() }
+    }
+}
+
+impl<'world, T> Drop for ResourceMut<'world, T> {
+    fn drop(&mut self) {
+        self.data.borrow.release_mut();
+    }
+}
+
+/// A shared borrow of a thing's part, which stops queries from writing the part until it's
+/// dropped.
+pub struct PartRef<'world, T: ?Sized> {
+    value: *const T,
+    /// `None` for tags, which have no storage to protect.
+    borrow: Option<&'world BorrowFlag>,
+    _marker: PhantomData<&'world T>,
+}
+
+impl<'world, T: ?Sized> PartRef<'world, T> {
+    #[inline]
+    fn new(value: *const T, borrow: Option<&'world BorrowFlag>) -> Self {
+        Self {
+            value,
+            borrow,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<'world, T: ?Sized> Deref for PartRef<'world, T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe { &*self.value }
+    }
+}
+
+impl<'world, T: ?Sized> Drop for PartRef<'world, T> {
+    fn drop(&mut self) {
+        if let Some(borrow) = self.borrow {
+            borrow.release();
+        }
+    }
+}
+
+pub struct Factory<'world, 'registry> {
+    world: &'world mut World<'registry>,
+}
+
+impl<'world, 'registry> Factory<'world, 'registry> {
+    pub fn thing(&mut self) -> ThingBuilder<'_, 'registry> {
+        ThingBuilder {
+            world: self.world,
+            key: ArchtypeKey::default(),
+            parts: Vec::new(),
+            vectors: Vec::new(),
+            shared: Vec::new(),
+            data: Vec::new(),
+        }
+    }
+}
+
+pub struct ThingBuilder<'world, 'registry> {
+    world: &'world mut World<'registry>,
+    key: ArchtypeKey,
+    parts: Vec<(usize, usize)>,
+    vectors: Vec<(usize, usize, usize)>,
+    shared: Vec<(usize, SharedId)>,
+    data: Vec<u8>,
+}
+
+impl<'world, 'registry> ThingBuilder<'world, 'registry> {
+    pub fn add_part<T: Blit + Any>(mut self, part: T) -> Self {
+        let index = self
+            .world
+            .registry
+            .part_index::<T>()
+            .expect("part type not registered");
+        assert!(
+            !self.key.scalar_parts.contains(index),
+            "part added to thing more than once"
+        );
+        self.key.scalar_parts.set(index);
+
+        let offset = self.data.len();
+        self.data.reserve(size_of::<T>());
+        unsafe {
+            self.data
+                .as_mut_ptr()
+                .add(offset)
+                .cast::<T>()
+                .write_unaligned(part);
+            self.data.set_len(offset + size_of::<T>());
+        }
+        self.parts.push((index, offset));
+        self
+    }
+
+    pub fn add_vector_part<T: Blit + Any>(mut self, mut values: Vec<T>) -> Self {
+        let index = self
+            .world
+            .registry
+            .part_index::<T>()
+            .expect("part type not registered");
+        assert!(
+            !self.key.vector_parts.contains(index),
+            "vector part added to thing more than once"
+        );
+        self.key.vector_parts.set(index);
+
+        let offset = self.data.len();
+        let size = values.len() * size_of::<T>();
+        self.data.reserve(size);
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                values.as_ptr().cast::<u8>(),
+                self.data.as_mut_ptr().add(offset),
+                size,
+            );
+            self.data.set_len(offset + size);
+            self.vectors.push((index, offset, values.len()));
+            values.set_len(0);
+        }
+        self
+    }
+
+    /// Adds a shared part, stored once per chunk for all the things with an equal value rather
+    /// than once per thing.
+    pub fn add_shared_part<T: Blit + Any + PartialEq>(mut self, part: T) -> Self {
+        let index = self
+            .world
+            .registry
+            .part_index::<T>()
+            .expect("part type not registered");
+        assert!(
+            !self.key.shared_parts.contains(index),
+            "shared part added to thing more than once"
+        );
+        self.key.shared_parts.set(index);
+
+        let id = self.world.intern_shared(index, &part);
+        self.shared.push((index, id));
+        self
+    }
+
+    pub fn finish(mut self) -> ThingId {
+        self.shared.sort_unstable_by_key(|&(part, _)| part);
+        let shared = self.shared.iter().map(|&(_, id)| id).collect::<Vec<_>>();
+        self.world.spawn(
+            None,
+            &self.key,
+            &self.parts,
+            &self.vectors,
+            &shared,
+            &self.data,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Copy, Clone, PartialEq, Debug)]
+    struct Position(f32, f32, f32);
+
+    #[derive(Copy, Clone, PartialEq, Debug)]
+    struct Health(u8);
+
+    unsafe impl Blit for Position {}
+    unsafe impl Blit for Health {}
+
+    #[test]
+    fn create_destroy() {
+        let registry = Registry::new();
+        let world = World::new(&registry);
+        drop(world);
+    }
+
+    #[test]
+    fn factory() {
+        let mut registry = Registry::new();
+        registry.register_part::<Position>();
+        registry.register_part::<Health>();
+        let mut world = World::new(&registry);
+
+        let mut factory = world.factory();
+        let a = factory
+            .thing()
+            .add_part(Position(1.0, 2.0, 3.0))
+            .add_part(Health(100))
+            .finish();
+        let b = factory.thing().add_part(Health(50)).finish();
+        let c = factory
+            .thing()
+            .add_part(Health(25))
+            .add_part(Position(4.0, 5.0, 6.0))
+            .finish();
+
+        assert_eq!(world.archtypes.len(), 2);
+        assert_eq!(
+            world.get::<Position>(a).as_deref(),
+            Some(&Position(1.0, 2.0, 3.0))
+        );
+        assert_eq!(world.get::<Health>(a).as_deref(), Some(&Health(100)));
+        assert_eq!(world.get::<Position>(b).as_deref(), None);
+        assert_eq!(world.get::<Health>(b).as_deref(), Some(&Health(50)));
+        assert_eq!(
+            world.get::<Position>(c).as_deref(),
+            Some(&Position(4.0, 5.0, 6.0))
+        );
+
+        world.get_mut::<Health>(c).unwrap().0 = 75;
+        assert_eq!(world.get::<Health>(c).as_deref(), Some(&Health(75)));
+    }
+
+    #[test]
+    fn destroy() {
+        let mut registry = Registry::new();
+        registry.register_part::<Position>();
+        registry.register_part::<Health>();
+        let mut world = World::new(&registry);
+
+        let things = (0..100)
+            .map(|i| {
+                world
+                    .factory()
+                    .thing()
+                    .add_part(Position(i as f32, 0.0, 0.0))
+                    .add_part(Health(i as u8))
+                    .finish()
+            })
+            .collect::<Vec<_>>();
+
+        for &thing in things.iter().step_by(3) {
+            assert!(world.destroy(thing));
+        }
+
+        for (i, &thing) in things.iter().enumerate() {
+            if i % 3 == 0 {
+                assert!(!world.contains(thing));
+                assert_eq!(world.get::<Position>(thing).as_deref(), None);
+                assert!(!world.destroy(thing));
+            } else {
+                assert_eq!(
+                    world.get::<Position>(thing).as_deref(),
+                    Some(&Position(i as f32, 0.0, 0.0))
+                );
+                assert_eq!(
+                    world.get::<Health>(thing).as_deref(),
+                    Some(&Health(i as u8))
+                );
+            }
+        }
+
+        assert_eq!(world.things.len(), 66);
+        assert_eq!(world.chunks[0].len, 66);
+
+        let thing = world.factory().thing().add_part(Health(200)).finish();
+        assert!(!things.contains(&thing));
+        assert_eq!(world.get::<Health>(thing).as_deref(), Some(&Health(200)));
+    }
+
+    #[test]
+    fn add_remove_part() {
+        let mut registry = Registry::new();
+        registry.register_part::<Position>();
+        registry.register_part::<Health>();
+        let mut world = World::new(&registry);
+
+        let things = (0..10)
+            .map(|i| {
+                world
+                    .factory()
+                    .thing()
+                    .add_part(Position(i as f32, 0.0, 0.0))
+                    .finish()
+            })
+            .collect::<Vec<_>>();
+
+        for (i, &thing) in things.iter().enumerate().filter(|(i, _)| i % 2 == 0) {
+            assert!(world.add_part(thing, Health(i as u8)));
+        }
+        assert_eq!(world.archtypes.len(), 2);
+
+        for (i, &thing) in things.iter().enumerate() {
+            assert_eq!(
+                world.get::<Position>(thing).as_deref(),
+                Some(&Position(i as f32, 0.0, 0.0))
+            );
+            if i % 2 == 0 {
+                assert_eq!(
+                    world.get::<Health>(thing).as_deref(),
+                    Some(&Health(i as u8))
+                );
+            } else {
+                assert_eq!(world.get::<Health>(thing).as_deref(), None);
+            }
+        }
+
+        assert!(world.add_part(things[0], Health(42)));
+        assert_eq!(world.get::<Health>(things[0]).as_deref(), Some(&Health(42)));
+
+        assert_eq!(world.remove_part::<Health>(things[1]), None);
+        assert_eq!(world.remove_part::<Health>(things[0]), Some(Health(42)));
+        assert_eq!(
+            world.remove_part::<Position>(things[0]),
+            Some(Position(0.0, 0.0, 0.0))
+        );
+        assert_eq!(world.get::<Position>(things[0]).as_deref(), None);
+        assert_eq!(
+            world.get::<Position>(things[2]).as_deref(),
+            Some(&Position(2.0, 0.0, 0.0))
+        );
+        assert_eq!(world.get::<Health>(things[2]).as_deref(), Some(&Health(2)));
+
+        assert!(world.destroy(things[0]));
+        assert!(!world.add_part(things[0], Health(1)));
+        assert_eq!(world.remove_part::<Health>(things[0]), None);
+    }
+
+    #[test]
+    fn vector_parts() {
+        let mut registry = Registry::new();
+        registry.register_part::<Position>();
+        registry.register_part::<Health>();
+        let mut world = World::new(&registry);
+
+        let things = (0..10)
+            .map(|i| {
+                world
+                    .factory()
+                    .thing()
+                    .add_part(Health(i as u8))
+                    .add_vector_part((0..i).map(|j| Position(j as f32, 0.0, 0.0)).collect())
+                    .finish()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(world.archtypes.len(), 1);
+
+        for (i, &thing) in things.iter().enumerate() {
+            let positions = world.get_vector_part::<Position>(thing).unwrap();
+            assert_eq!(positions.len(), i);
+            assert!(positions
+                .iter()
+                .enumerate()
+                .all(|(j, position)| position.0 == j as f32));
+            assert_eq!(world.get_vector_part::<Health>(thing).as_deref(), None);
+        }
+
+        assert!(world.push_vector_part(things[0], Position(9.0, 9.0, 9.0)));
+        world.get_vector_part_mut::<Position>(things[0]).unwrap()[0].1 = 1.0;
+        assert_eq!(
+            world.pop_vector_part::<Position>(things[0]),
+            Some(Position(9.0, 1.0, 9.0))
+        );
+        assert_eq!(world.pop_vector_part::<Position>(things[0]), None);
+
+        // Pushing to a thing without the vector part migrates it.
+        assert!(world.push_vector_part(things[1], Health(1)));
+        assert_eq!(world.archtypes.len(), 2);
+        assert_eq!(
+            world.get_vector_part::<Health>(things[1]).as_deref(),
+            Some(&[Health(1)][..])
+        );
+        assert_eq!(world.get::<Health>(things[1]).as_deref(), Some(&Health(1)));
+        assert_eq!(
+            world.get_vector_part::<Position>(things[1]).unwrap().len(),
+            1
+        );
+
+        let removed = world.remove_vector_part::<Position>(things[3]).unwrap();
+        assert_eq!(removed.len(), 3);
+        assert_eq!(removed[2], Position(2.0, 0.0, 0.0));
+        assert_eq!(
+            world.get_vector_part::<Position>(things[3]).as_deref(),
+            None
+        );
+        assert_eq!(world.remove_vector_part::<Position>(things[3]), None);
+
+        assert!(world.destroy(things[5]));
+        assert_eq!(
+            world.get_vector_part::<Position>(things[5]).as_deref(),
+            None
+        );
+        assert_eq!(
+            world.get_vector_part::<Position>(things[9]).unwrap().len(),
+            9
+        );
+
+        // The elements stay borrowed until the guard is dropped.
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _positions = world.get_vector_part::<Position>(things[9]);
+            world.query::<&mut [Position]>();
+        }));
+        let message = *result.unwrap_err().downcast::<String>().unwrap();
+        assert!(
+            message.contains("Position` is already borrowed by"),
+            "{}",
+            message
+        );
+        world.query::<&mut [Position]>();
+    }
+
+    #[test]
+    fn shared_parts() {
+        let mut registry = Registry::new();
+        registry.register_part::<Position>();
+        registry.register_part::<Health>();
+        let mut world = World::new(&registry);
+
+        let things = (0..2000)
+            .map(|i| {
+                world
+                    .factory()
+                    .thing()
+                    .add_part(Position(i as f32, 0.0, 0.0))
+                    .add_shared_part(Health(i as u8 % 2))
+                    .finish()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(world.archtypes.len(), 1);
+        assert_eq!(world.shared_values.len(), 2);
+
+        // Every chunk holds only things sharing a single value.
+        let archtype = &world.archtypes[0];
+        let capacity = archtype.layout.capacity as usize;
+        assert_eq!(archtype.chunks.len(), 2 * 1000_usize.div_ceil(capacity));
+        for (i, &thing) in things.iter().enumerate() {
+            assert_eq!(
+                world.get_shared_part::<Health>(thing),
+                Some(&Health(i as u8 % 2))
+            );
+            assert_eq!(world.get::<Health>(thing).as_deref(), None);
+        }
+
+        assert!(world.add_shared_part(things[0], Health(1)));
+        assert_eq!(world.get_shared_part::<Health>(things[0]), Some(&Health(1)));
+        assert_eq!(
+            world.get::<Position>(things[0]).as_deref(),
+            Some(&Position(0.0, 0.0, 0.0))
+        );
+
+        // Adding and removing scalar parts keeps the shared value.
+        assert!(world.add_part(things[2], Health(50)));
+        assert_eq!(world.get::<Health>(things[2]).as_deref(), Some(&Health(50)));
+        assert_eq!(world.get_shared_part::<Health>(things[2]), Some(&Health(0)));
+
+        assert_eq!(
+            world.remove_shared_part::<Health>(things[4]),
+            Some(Health(0))
+        );
+        assert_eq!(world.get_shared_part::<Health>(things[4]), None);
+        assert_eq!(world.remove_shared_part::<Health>(things[4]), None);
+
+        let thing = world.factory().thing().add_part(Health(7)).finish();
+        assert!(world.add_shared_part(thing, Health(2)));
+        assert_eq!(world.get_shared_part::<Health>(thing), Some(&Health(2)));
+        assert_eq!(world.get::<Health>(thing).as_deref(), Some(&Health(7)));
+        assert_eq!(world.shared_values.len(), 3);
+    }
+
+    #[test]
+    fn shared_layout() {
+        #[allow(dead_code)]
+        #[derive(Copy, Clone)]
+        #[repr(align(16))]
+        struct Wide([f32; 4]);
+
+        unsafe impl Blit for Wide {}
+
+        let mut registry = Registry::new();
+        registry.register_part::<Health>();
+        registry.register_part::<Wide>();
+        registry.register_part::<u64>();
+
+        let mut key = ArchtypeKey::default();
+        key.scalar_parts.set(2);
+        key.shared_parts.set(0);
+        key.shared_parts.set(1);
+        let layout = ChunkLayout::new(&registry, &key);
+
+        let wide = layout.shared_column(1).unwrap();
+        let health = layout.shared_column(0).unwrap();
+        assert_eq!(wide.offset, 0);
+        assert_eq!(health.id % align_of::<SharedId>(), 0);
+        assert_eq!(layout.column(2).unwrap().offset % align_of::<u64>(), 0);
+        assert!(layout.column(2).unwrap().offset > health.offset);
+        assert!(layout.shared_column(2).is_none());
+    }
+
+    #[test]
+    fn part_bitmap() {
+        let mut a = PartBitmap::default();
+        let mut b = PartBitmap::default();
+        for index in [0, 63, 64, 130, 255] {
+            a.set(index);
+        }
+        for index in [63, 130, 200] {
+            b.set(index);
+        }
+        assert_eq!(a.count(), 5);
+        assert!(a.contains(255) && !a.contains(200));
+
+        let bits = |bitmap: PartBitmap| bitmap.iter_set_bits().collect::<Vec<_>>();
+        assert_eq!(bits(a.union(&b)), [0, 63, 64, 130, 200, 255]);
+        assert_eq!(bits(a.intersection(&b)), [63, 130]);
+        assert_eq!(bits(a.difference(&b)), [0, 64, 255]);
+        assert!(a.is_superset_of(&a.intersection(&b)));
+        assert!(!a.is_superset_of(&b));
+        assert!(a.difference(&b).is_disjoint_from(&b));
+
+        b.clear(200);
+        assert_eq!(a.intersection(&b), b);
+        assert!(b < a);
+        b.clear(63);
+        b.clear(130);
+        assert!(b.is_empty());
+        assert_eq!(b, PartBitmap::default());
+    }
+
+    #[test]
+    fn archtype_reuse() {
+        let mut registry = Registry::new();
+        registry.register_part::<Position>();
+        registry.register_part::<Health>();
+        let mut world = World::new(&registry);
+
+        let mut factory = world.factory();
+        let a = factory
+            .thing()
+            .add_part(Position(0.0, 0.0, 0.0))
+            .add_part(Health(1))
+            .finish();
+        let b = factory
+            .thing()
+            .add_part(Health(2))
+            .add_part(Position(1.0, 0.0, 0.0))
+            .finish();
+        let c = factory.thing().add_part(Health(3)).finish();
+        let d = factory.thing().add_vector_part(vec![Health(4)]).finish();
+        assert_eq!(world.archtypes.len(), 3);
+
+        // Reaching a combination of parts through edges finds the archtype spawning created.
+        world.add_part(c, Position(2.0, 0.0, 0.0));
+        world.push_vector_part(c, Health(5));
+        world.remove_part::<Health>(c);
+        assert_eq!(world.archtypes.len(), 5);
+        world.remove_part::<Position>(c);
+        assert_eq!(world.archtypes.len(), 5);
+
+        let archtype = |world: &World, thing| world.thing(thing).unwrap().archtype;
+        assert_eq!(archtype(&world, a), archtype(&world, b));
+        assert_eq!(archtype(&world, c), archtype(&world, d));
+    }
+
+    #[test]
+    fn archtype_edges() {
+        let mut registry = Registry::new();
+        registry.register_part::<Position>();
+        registry.register_part::<Health>();
+        let mut world = World::new(&registry);
+
+        let things = (0..4)
+            .map(|_| {
+                world
+                    .factory()
+                    .thing()
+                    .add_part(Position(0.0, 0.0, 0.0))
+                    .finish()
+            })
+            .collect::<Vec<_>>();
+
+        for _ in 0..2 {
+            for &thing in &things {
+                world.add_part(thing, Health(1));
+            }
+            for &thing in &things {
+                world.remove_part::<Health>(thing);
+            }
+        }
+
+        assert_eq!(world.archtypes.len(), 2);
+        let position = world.archtype_map[&world.archtypes[0].key];
+        let position_health = world.archtype_map[&world.archtypes[1].key];
+        let health_index = registry.part_index::<Health>().unwrap();
+        let edge = world.archtypes[0].edges[&health_index];
+        assert_eq!(edge.add, Some(position_health));
+        assert_eq!(edge.remove, None);
+        let edge = world.archtypes[1].edges[&health_index];
+        assert_eq!(edge.add, None);
+        assert_eq!(edge.remove, Some(position));
+    }
+
+    #[test]
+    fn chunk_layout() {
+        #[allow(dead_code)]
+        #[derive(Copy, Clone)]
+        #[repr(align(16))]
+        struct Wide([f32; 4]);
+
+        #[allow(dead_code)]
+        #[derive(Copy, Clone)]
+        struct Flag(u8);
+
+        unsafe impl Blit for Wide {}
+        unsafe impl Blit for Flag {}
+
+        let mut registry = Registry::new();
+        registry.register_part::<Flag>();
+        registry.register_part::<Position>();
+        registry.register_part::<Wide>();
+        registry.register_part::<u64>();
+
+        let mut key = ArchtypeKey::default();
+        for part in 0..4 {
+            key.scalar_parts.set(part);
+        }
+        key.vector_parts.set(1);
+        let layout = ChunkLayout::new(&registry, &key);
+
+        let stride =
+            size_of::<ThingId>() + 1 + 12 + 16 + 8 + size_of::<RawVector>() + 5 * size_of::<u32>();
+        // The header holds a tick for each of the five columns, padded to the widest alignment.
+        let header = 32;
+        assert_eq!(
+            layout.capacity as usize,
+            (CHUNK_SIZE_BYTES - header) / stride
+        );
+
+        let capacity = layout.capacity as usize;
+        let mut ranges = vec![(layout.ids, layout.ids + capacity * size_of::<ThingId>())];
+        for (column, align) in layout.columns.iter().zip([1, 4, 16, 8]) {
+            assert_eq!(column.offset % align, 0);
+            assert_eq!(column.ticks % align_of::<u32>(), 0);
+            ranges.push((column.offset, column.offset + capacity * column.width));
+            ranges.push((column.ticks, column.ticks + capacity * size_of::<u32>()));
+            ranges.push((column.chunk_tick, column.chunk_tick + size_of::<u32>()));
+        }
+        let vectors = layout.vector_column(1).unwrap();
+        assert_eq!(vectors.offset % align_of::<RawVector>(), 0);
+        ranges.push((
+            vectors.offset,
+            vectors.offset + capacity * size_of::<RawVector>(),
+        ));
+        ranges.push((vectors.ticks, vectors.ticks + capacity * size_of::<u32>()));
+        ranges.push((vectors.chunk_tick, vectors.chunk_tick + size_of::<u32>()));
+        ranges.sort_unstable();
+        for pair in ranges.windows(2) {
+            assert!(pair[0].1 <= pair[1].0);
+        }
+        assert!(ranges.last().unwrap().1 <= CHUNK_SIZE_BYTES);
+
+        for part in 0..4 {
+            assert_eq!(layout.column(part).unwrap().part, part);
+        }
+        assert!(layout.column(4).is_none());
+        assert!(layout.vector_column(0).is_none());
+    }
+
+    #[test]
+    fn fill_chunks() {
+        let mut registry = Registry::new();
+        registry.register_part::<Position>();
+        let mut world = World::new(&registry);
+
+        let things = (0..10_000)
+            .map(|i| {
+                world
+                    .factory()
+                    .thing()
+                    .add_part(Position(i as f32, 0.0, 0.0))
+                    .finish()
+            })
+            .collect::<Vec<_>>();
+
+        assert!(world.chunks.len() > 1);
+        for (i, &thing) in things.iter().enumerate() {
+            assert_eq!(
+                world.get::<Position>(thing).as_deref(),
+                Some(&Position(i as f32, 0.0, 0.0))
+            );
+        }
+    }
+
+    #[test]
+    fn resources() {
+        #[derive(Copy, Clone, PartialEq, Debug)]
+        struct Clock(u64);
+
+        #[derive(Copy, Clone, PartialEq, Debug)]
+        #[repr(align(64))]
+        struct Terrain(u32);
+
+        #[derive(Copy, Clone, PartialEq, Debug)]
+        struct Paused;
+
+        unsafe impl Blit for Clock {}
+        unsafe impl Blit for Terrain {}
+        unsafe impl Blit for Paused {}
+
+        let mut registry = Registry::new();
+        registry.register_part::<Health>();
+        registry.register_resource::<Clock>();
+        registry.register_resource::<Terrain>();
+        registry.register_resource::<Paused>();
+        let mut world = World::new(&registry);
+
+        assert!(world.resource::<Clock>().is_none());
+        assert_eq!(world.insert_resource(Clock(1)), None);
+        assert_eq!(world.insert_resource(Terrain(7)), None);
+        assert_eq!(world.insert_resource(Paused), None);
+        assert_eq!(world.insert_resource(Clock(2)), Some(Clock(1)));
+
+        {
+            let clock = world.resource::<Clock>().unwrap();
+            let also_clock = world.resource::<Clock>().unwrap();
+            let mut terrain = world.resource_mut::<Terrain>().unwrap();
+            assert_eq!(&*terrain as *const Terrain as usize % 64, 0);
+            terrain.0 += clock.0 as u32 + also_clock.0 as u32;
+            assert_eq!(*world.resource::<Paused>().unwrap(), Paused);
+        }
+        world.resource_mut::<Clock>().unwrap().0 += 1;
+        assert_eq!(*world.resource::<Clock>().unwrap(), Clock(3));
+        assert_eq!(*world.resource::<Terrain>().unwrap(), Terrain(11));
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _clock = world.resource::<Clock>();
+            world.resource_mut::<Clock>();
+        }));
+        let message = *result.unwrap_err().downcast::<String>().unwrap();
+        assert!(
+            message.contains("Clock` is already borrowed"),
+            "{}",
+            message
+        );
+
+        assert_eq!(world.remove_resource::<Clock>(), Some(Clock(3)));
+        assert_eq!(world.remove_resource::<Clock>(), None);
+        assert!(world.resource_mut::<Clock>().is_none());
+
+        // Resources share the part index space without being parts.
+        assert_eq!(registry.part_index::<Clock>(), None);
+        let clock = registry.resource_index::<Clock>().unwrap();
+        assert_ne!(Some(clock), registry.part_index::<Health>());
+        assert!(registry.part_bitmap::<(Health, Clock)>().contains(clock));
+    }
+
+    #[test]
+    fn tags() {
+        #[derive(Copy, Clone, PartialEq, Debug)]
+        struct Player;
+
+        #[derive(Copy, Clone, PartialEq, Debug)]
+        struct Dead;
+
+        unsafe impl Blit for Player {}
+        unsafe impl Blit for Dead {}
+
+        let mut registry = Registry::new();
+        registry.register_part::<Health>();
+        registry.register_part::<Player>();
+        registry.register_part::<Dead>();
+        let mut world = World::new(&registry);
+
+        let mut factory = world.factory();
+        let plain = factory.thing().add_part(Health(1)).finish();
+        let player = factory
+            .thing()
+            .add_part(Player)
+            .add_part(Health(2))
+            .finish();
+
+        // Tags only change the archtype key, not the chunk layout.
+        assert_eq!(world.archtypes.len(), 2);
+        let (a, b) = (&world.archtypes[0], &world.archtypes[1]);
+        assert!(a.key != b.key);
+        assert_eq!(b.layout.columns.len(), 1);
+        assert_eq!(a.layout.capacity, b.layout.capacity);
+        assert!(b.column(1).is_none());
+
+        assert_eq!(world.get::<Player>(player).as_deref(), Some(&Player));
+        assert_eq!(world.get::<Player>(plain).as_deref(), None);
+        assert_eq!(world.get_mut::<Player>(player), Some(&mut Player));
+
+        assert!(world.add_part(player, Dead));
+        assert!(world.add_part(player, Dead));
+        assert_eq!(world.get::<Dead>(player).as_deref(), Some(&Dead));
+        assert_eq!(world.get::<Health>(player).as_deref(), Some(&Health(2)));
+        assert_eq!(world.remove_part::<Player>(player), Some(Player));
+        assert_eq!(world.remove_part::<Player>(player), None);
+        assert_eq!(world.get::<Dead>(player).as_deref(), Some(&Dead));
+        assert_eq!(world.get::<Health>(player).as_deref(), Some(&Health(2)));
+        assert_eq!(world.get::<Health>(plain).as_deref(), Some(&Health(1)));
+    }
+
+    #[test]
+    fn sparse_parts() {
+        #[derive(Copy, Clone, PartialEq, Debug)]
+        struct Stunned(u32);
+
+        #[derive(Copy, Clone, PartialEq, Debug)]
+        struct Selected;
+
+        unsafe impl Blit for Stunned {}
+        unsafe impl Blit for Selected {}
+
+        let mut registry = Registry::new();
+        registry.register_part::<Health>();
+        registry.register_sparse_part::<Stunned>();
+        registry.register_sparse_part::<Selected>();
+        let mut world = World::new(&registry);
+
+        let mut factory = world.factory();
+        let a = factory.thing().add_part(Health(1)).finish();
+        let b = factory
+            .thing()
+            .add_part(Health(2))
+            .add_part(Stunned(3))
+            .finish();
+
+        // Sparse parts never change a thing's archtype.
+        assert_eq!(world.archtypes.len(), 1);
+        assert_eq!(world.get::<Stunned>(a).as_deref(), None);
+        assert_eq!(world.get::<Stunned>(b).as_deref(), Some(&Stunned(3)));
+
+        assert!(world.add_part(a, Stunned(4)));
+        assert!(world.add_part(a, Selected));
+        assert_eq!(world.archtypes.len(), 1);
+        world.get_mut::<Stunned>(a).unwrap().0 += 1;
+        assert_eq!(world.get::<Stunned>(a).as_deref(), Some(&Stunned(5)));
+        assert_eq!(world.get::<Selected>(a).as_deref(), Some(&Selected));
+        assert_eq!(world.get::<Selected>(b).as_deref(), None);
+
+        assert_eq!(world.remove_part::<Stunned>(b), Some(Stunned(3)));
+        assert_eq!(world.remove_part::<Stunned>(b), None);
+        assert_eq!(world.get::<Health>(b).as_deref(), Some(&Health(2)));
+
+        // Destroying a thing clears its slot, so a thing later reusing the slot starts without the
+        // part.
+        assert!(world.destroy(a));
+        assert!(!world.add_part(a, Stunned(6)));
+        let part = registry.part_index::<Stunned>().unwrap();
+        let stunned = world.sparse_set(part).unwrap().occupancy();
+        assert!(!stunned.contains(a.slot()));
+    }
+
+    #[test]
+    fn stable_sparse_parts() {
+        #[derive(Copy, Clone, PartialEq, Debug)]
+        struct Handle(u64);
+
+        unsafe impl Blit for Handle {}
+
+        let mut registry = Registry::new();
+        registry.register_part::<Health>();
+        registry.register_stable_sparse_part::<Handle>();
+        let mut world = World::new(&registry);
+
+        let first = world.factory().thing().add_part(Handle(0)).finish();
+        let pointer = world
+            .get::<Handle>(first)
+            .map(|handle| &*handle as *const Handle);
+        let things = (1..10_000)
+            .map(|_| world.factory().thing().add_part(Health(1)).finish())
+            .collect::<Vec<_>>();
+        for (i, &thing) in things.iter().enumerate() {
+            world.add_part(thing, Handle(i as u64 + 1));
+        }
+
+        // Adding the part to other things doesn't move existing values.
+        assert_eq!(
+            world
+                .get::<Handle>(first)
+                .map(|handle| &*handle as *const Handle),
+            pointer
+        );
+        assert_eq!(unsafe { *pointer.unwrap() }, Handle(0));
+        assert_eq!(
+            world.get::<Handle>(things[99]).as_deref(),
+            Some(&Handle(100))
+        );
+        let count = world.query::<&Handle>().iter().count();
+        assert_eq!(count, 10_000);
     }
 }